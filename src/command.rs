@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use crate::form::percent_decode_path;
+use crate::{parse_response, CgiError, RequestExt, Response};
+
+/// Run an external CGI programme (in any language) for a request, like a webserver would.
+///
+/// The programme gets the RFC 3875 meta-variables as its environment (plus `PATH`), and the
+/// request body on stdin. What it writes to stdout is parsed into a `Response`. Its stderr is
+/// passed through to ours.
+///
+/// ```rust,no_run
+/// extern crate cgi;
+///
+/// cgi::cgi_main! { |request: cgi::Request| -> cgi::Response {
+///     let legacy = cgi::CgiCommand::new("/usr/lib/cgi-bin/guestbook.pl")
+///         .script_name("/guestbook")
+///         .remote_addr("192.0.2.1");
+///     cgi::err_to_500(legacy.run(&request))
+/// } }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CgiCommand {
+    program: PathBuf,
+    current_dir: Option<PathBuf>,
+    script_name: String,
+    vars: Vec<(String, String)>,
+}
+
+impl CgiCommand {
+    /// Run the programme at this path
+    pub fn new(program: impl Into<PathBuf>) -> CgiCommand {
+        CgiCommand {
+            program: program.into(),
+            current_dir: None,
+            script_name: String::new(),
+            vars: Vec::new(),
+        }
+    }
+
+    /// Run the programme in this directory (by default, the current directory)
+    pub fn current_dir(mut self, dir: impl Into<PathBuf>) -> CgiCommand {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    /// The URL path the programme is at (`SCRIPT_NAME`). The rest of the request's path is
+    /// `PATH_INFO`.
+    pub fn script_name(mut self, script_name: impl Into<String>) -> CgiCommand {
+        self.script_name = script_name.into();
+        self
+    }
+
+    /// The address of the client (`REMOTE_ADDR`). By default it's the `REMOTE_ADDR` of the request
+    /// if it came from this crate, otherwise `127.0.0.1`.
+    pub fn remote_addr(self, remote_addr: impl Into<String>) -> CgiCommand {
+        self.var("REMOTE_ADDR", remote_addr)
+    }
+
+    /// Set any meta-variable, e.g. `SERVER_NAME` or `DOCUMENT_ROOT`. This overrides the ones
+    /// calculated from the request.
+    pub fn var(mut self, name: impl Into<String>, value: impl Into<String>) -> CgiCommand {
+        self.vars.push((name.into(), value.into()));
+        self
+    }
+
+    /// The meta-variables the programme would get for this request.
+    ///
+    /// `SERVER_NAME` & `SERVER_PORT` come from the request's URI if it's absolute, otherwise from
+    /// its `Host` header.
+    pub fn env_vars<B: AsRef<[u8]>>(&self, request: &http::Request<B>) -> HashMap<String, String> {
+        let mut env_vars = HashMap::new();
+        let mut set = |name: &str, value: &str| {
+            env_vars.insert(name.to_owned(), value.to_owned());
+        };
+
+        let uri = request.uri();
+        let https = uri.scheme() == Some(&http::uri::Scheme::HTTPS);
+        let host = request
+            .headers()
+            .get(http::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<http::uri::Authority>().ok());
+        let authority = uri.authority().cloned().or(host);
+        let default_port = if https { 443 } else { 80 };
+
+        set("GATEWAY_INTERFACE", "CGI/1.1");
+        set(
+            "SERVER_SOFTWARE",
+            concat!("rust-cgi/", env!("CARGO_PKG_VERSION")),
+        );
+        set(
+            "SERVER_PROTOCOL",
+            match request.version() {
+                http::Version::HTTP_09 => "HTTP/0.9",
+                http::Version::HTTP_10 => "HTTP/1.0",
+                http::Version::HTTP_2 => "HTTP/2",
+                http::Version::HTTP_3 => "HTTP/3",
+                _ => "HTTP/1.1",
+            },
+        );
+        set(
+            "SERVER_NAME",
+            authority.as_ref().map_or("localhost", |a| a.host()),
+        );
+        set(
+            "SERVER_PORT",
+            &authority
+                .as_ref()
+                .and_then(|a| a.port_u16())
+                .unwrap_or(default_port)
+                .to_string(),
+        );
+        if https {
+            set("HTTPS", "on");
+        }
+        set(
+            "REMOTE_ADDR",
+            &request
+                .cgi_meta()
+                .and_then(|meta| meta.remote_addr)
+                .map_or_else(|| "127.0.0.1".to_owned(), |addr| addr.to_string()),
+        );
+        set("REQUEST_METHOD", request.method().as_str());
+        set(
+            "REQUEST_URI",
+            uri.path_and_query().map_or("/", |pq| pq.as_str()),
+        );
+        set("SCRIPT_NAME", &self.script_name);
+        let path_info = path_info(uri.path(), &self.script_name);
+        if !path_info.is_empty() {
+            set(
+                "PATH_INFO",
+                &String::from_utf8_lossy(&percent_decode_path(path_info.as_bytes())),
+            );
+        }
+        set("QUERY_STRING", uri.query().unwrap_or_default());
+        let body = request.body().as_ref();
+        if !body.is_empty() {
+            set("CONTENT_LENGTH", &body.len().to_string());
+        }
+
+        add_header_vars(
+            &mut env_vars,
+            request
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.as_str(), String::from_utf8_lossy(value.as_bytes()))),
+        );
+
+        for (name, value) in &self.vars {
+            env_vars.insert(name.clone(), value.clone());
+        }
+
+        env_vars
+    }
+
+    /// Run the programme for this request, and parse its output.
+    ///
+    /// Fails if the programme can't be started, or its output isn't a valid CGI response (see
+    /// [`CgiError::status`](enum.CgiError.html#method.status) for what to send the client then).
+    pub fn run<B: AsRef<[u8]>>(&self, request: &http::Request<B>) -> Result<Response, CgiError> {
+        let output = self.output(self.env_vars(request), request.body().as_ref())?;
+        parse_response(&output)
+    }
+
+    /// Run the programme with this environment & stdin, returning what it wrote to stdout
+    pub(crate) fn output(
+        &self,
+        env_vars: HashMap<String, String>,
+        body: &[u8],
+    ) -> std::io::Result<Vec<u8>> {
+        let mut command = Command::new(&self.program);
+        command.env_clear().envs(&env_vars);
+        if let Some(path) = std::env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+        if let Some(dir) = &self.current_dir {
+            command.current_dir(dir);
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+
+        // Write the body on another thread, so a programme which writes before it has read all of
+        // its input can't deadlock with us
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let body = body.to_vec();
+        let writer = std::thread::spawn(move || {
+            // The programme doesn't have to read the body
+            let _ = stdin.write_all(&body);
+        });
+
+        let output = child.wait_with_output()?;
+        let _ = writer.join();
+        Ok(output.stdout)
+    }
+}
+
+/// The part of `path` after the script, i.e. `PATH_INFO` (still URL-encoded). `script_name` only
+/// matches whole segments, so `/app` is the script for `/app` & `/app/x`, but not `/application`.
+/// If `path` isn't under `script_name`, all of it is the `PATH_INFO`.
+pub(crate) fn path_info<'a>(path: &'a str, script_name: &str) -> &'a str {
+    let script_name = script_name.trim_end_matches('/');
+    match path.strip_prefix(script_name) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+        _ => path,
+    }
+}
+
+/// Add request headers as `HTTP_*` meta-variables (`Content-Type` as `CONTENT_TYPE`), joining
+/// repeated headers into one like webservers do. `Content-Length` is skipped, as it's set from the
+/// body.
+///
+/// Like Apache & nginx, `Proxy` is dropped, as many programmes would take `HTTP_PROXY` as the
+/// proxy for their own requests (httpoxy), and so are names with `_`, which would otherwise be
+/// indistinguishable from the `-` version, e.g. `X_Forwarded_For` from `X-Forwarded-For`.
+pub(crate) fn add_header_vars<N, V>(
+    env_vars: &mut HashMap<String, String>,
+    headers: impl IntoIterator<Item = (N, V)>,
+) where
+    N: AsRef<str>,
+    V: AsRef<str>,
+{
+    for (name, value) in headers {
+        let name = name.as_ref();
+        if name.contains('_') || name.eq_ignore_ascii_case("proxy") {
+            continue;
+        }
+        let value = value.as_ref();
+        let var = match name.to_ascii_uppercase().replace('-', "_") {
+            var if var == "CONTENT_LENGTH" => continue,
+            var if var == "CONTENT_TYPE" => var,
+            var => format!("HTTP_{}", var),
+        };
+        let separator = if var == "HTTP_COOKIE" { "; " } else { ", " };
+        env_vars
+            .entry(var)
+            .and_modify(|existing| {
+                existing.push_str(separator);
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_owned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use crate::test_util::Script;
+
+    #[test]
+    fn test_env_vars() {
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("https://example.com:8443/guestbook/sign/caf%C3%A9?lang=en")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Content-Length", "999")
+            .header("Cookie", "a=1")
+            .header("Cookie", "b=2")
+            .header("X-Forwarded-For", "192.0.2.1")
+            .body(b"name=Amanda".to_vec())
+            .unwrap();
+        let env_vars = CgiCommand::new("/bin/true")
+            .script_name("/guestbook")
+            .remote_addr("192.0.2.1")
+            .var("DOCUMENT_ROOT", "/var/www")
+            .env_vars(&request);
+
+        let var = |name: &str| env_vars.get(name).map(String::as_str);
+        assert_eq!(var("GATEWAY_INTERFACE"), Some("CGI/1.1"));
+        assert_eq!(var("SERVER_PROTOCOL"), Some("HTTP/1.1"));
+        assert_eq!(var("SERVER_NAME"), Some("example.com"));
+        assert_eq!(var("SERVER_PORT"), Some("8443"));
+        assert_eq!(var("HTTPS"), Some("on"));
+        assert_eq!(var("REQUEST_METHOD"), Some("POST"));
+        assert_eq!(var("SCRIPT_NAME"), Some("/guestbook"));
+        assert_eq!(var("PATH_INFO"), Some("/sign/café"));
+        assert_eq!(var("QUERY_STRING"), Some("lang=en"));
+        assert_eq!(var("CONTENT_LENGTH"), Some("11"));
+        assert_eq!(
+            var("CONTENT_TYPE"),
+            Some("application/x-www-form-urlencoded")
+        );
+        assert_eq!(var("HTTP_COOKIE"), Some("a=1; b=2"));
+        assert_eq!(var("HTTP_X_FORWARDED_FOR"), Some("192.0.2.1"));
+        assert_eq!(var("REMOTE_ADDR"), Some("192.0.2.1"));
+        assert_eq!(var("DOCUMENT_ROOT"), Some("/var/www"));
+
+        let request = http::Request::builder()
+            .uri("/")
+            .header("Host", "example.org")
+            .body(Vec::new())
+            .unwrap();
+        let env_vars = CgiCommand::new("/bin/true").env_vars(&request);
+        assert_eq!(env_vars["SERVER_NAME"], "example.org");
+        assert_eq!(env_vars["SERVER_PORT"], "80");
+        assert_eq!(env_vars["QUERY_STRING"], "");
+        assert!(!env_vars.contains_key("CONTENT_LENGTH"));
+    }
+
+    #[test]
+    fn test_path_info() {
+        assert_eq!(path_info("/guestbook", "/guestbook"), "");
+        assert_eq!(path_info("/guestbook/sign", "/guestbook"), "/sign");
+        assert_eq!(path_info("/guestbook/sign", "/guestbook/"), "/sign");
+        assert_eq!(path_info("/guestbookx", "/guestbook"), "/guestbookx");
+        assert_eq!(path_info("/other", "/guestbook"), "/other");
+        assert_eq!(path_info("/x", ""), "/x");
+
+        let request = http::Request::builder()
+            .uri("/guestbookx")
+            .body(Vec::new())
+            .unwrap();
+        let env_vars = CgiCommand::new("/bin/true")
+            .script_name("/guestbook")
+            .env_vars(&request);
+        assert_eq!(env_vars["PATH_INFO"], "/guestbookx");
+        // RFC 3875 requires REMOTE_ADDR
+        assert_eq!(env_vars["REMOTE_ADDR"], "127.0.0.1");
+    }
+
+    #[test]
+    fn test_dropped_headers() {
+        let request = http::Request::builder()
+            .uri("/")
+            .header("Proxy", "http://evil.example:8080")
+            .header("X-Forwarded-For", "192.0.2.1")
+            .header("X_Forwarded_For", "203.0.113.9")
+            .header("X_Other", "1")
+            .body(Vec::new())
+            .unwrap();
+        let env_vars = CgiCommand::new("/bin/true").env_vars(&request);
+
+        // httpoxy: the programme would use this as the proxy for its own requests
+        assert!(!env_vars.contains_key("HTTP_PROXY"));
+        // The `_` version can't be merged into (or mistaken for) the real header
+        assert_eq!(env_vars["HTTP_X_FORWARDED_FOR"], "192.0.2.1");
+        assert!(!env_vars.contains_key("HTTP_X_OTHER"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run() {
+        let script = Script::new(
+            "command-run",
+            "printf 'Status: 201 Created\\r\\nContent-Type: text/plain\\r\\n\\r\\n%s %s %s ' \"$GREETING\" \"$REQUEST_METHOD\" \"$PATH_INFO\"\ncat\n",
+        );
+        let request = http::Request::builder()
+            .method("PUT")
+            .uri("/app/x?y")
+            .body(b"the body".to_vec())
+            .unwrap();
+        let response = CgiCommand::new(script.path())
+            .script_name("/app")
+            .var("GREETING", "Hello")
+            .run(&request)
+            .unwrap();
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        assert_eq!(response.body(), b"Hello PUT /x the body");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_errors() {
+        let request = http::Request::new(Vec::new());
+
+        let err = CgiCommand::new("/does/not/exist")
+            .run(&request)
+            .unwrap_err();
+        assert!(matches!(err, CgiError::Io(_)));
+
+        let script = Script::new("command-invalid", "echo 'not a CGI response'\n");
+        let err = CgiCommand::new(script.path()).run(&request).unwrap_err();
+        assert!(matches!(err, CgiError::InvalidResponse(_)));
+        assert_eq!(err.status(), 502);
+    }
+}
@@ -0,0 +1,305 @@
+use std::time::{Duration, SystemTime};
+
+use crate::{http_date, CgiError};
+
+/// The cookies the client sent, from the `Cookie` header(s).
+///
+/// ```rust
+/// extern crate cgi;
+///
+/// cgi::cgi_main! { |request: cgi::Request| -> cgi::Response {
+///     let cookies = cgi::CookieJar::from_request(&request);
+///     let visits: u32 = cookies.get("visits").and_then(|v| v.parse().ok()).unwrap_or(0);
+///
+///     let mut response = cgi::text_response(200, format!("Visit number {}", visits + 1));
+///     let cookie = cgi::Cookie::new("visits", (visits + 1).to_string()).path("/").http_only(true);
+///     cgi::set_cookie(&mut response, &cookie).unwrap();
+///     response
+/// } }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CookieJar {
+    cookies: Vec<(String, String)>,
+}
+
+impl CookieJar {
+    /// The cookies sent with this request
+    pub fn from_request<B>(request: &http::Request<B>) -> CookieJar {
+        let mut jar = CookieJar::default();
+        // HTTP/2 clients can split cookies over several headers
+        for header in request.headers().get_all(http::header::COOKIE) {
+            let header = String::from_utf8_lossy(header.as_bytes());
+            for pair in header.split(';') {
+                let mut parts = pair.splitn(2, '=');
+                let name = parts.next().unwrap_or_default().trim();
+                let value = parts.next().unwrap_or_default().trim();
+                if name.is_empty() {
+                    continue;
+                }
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                jar.cookies.push((name.to_owned(), value.to_owned()));
+            }
+        }
+        jar
+    }
+
+    /// The value of the (first) cookie with this name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.cookies
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All the cookies, as `(name, value)`, in the order the client sent them
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.cookies.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// How many cookies there are
+    pub fn len(&self) -> usize {
+        self.cookies.len()
+    }
+
+    /// Whether the client sent no cookies
+    pub fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+}
+
+/// The `SameSite` attribute of a cookie
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    /// Only sent for requests from the same site
+    Strict,
+    /// Also sent when following a link from another site
+    Lax,
+    /// Sent with all requests (requires `Secure`)
+    None,
+}
+
+/// A cookie to set on the client, with a `Set-Cookie` header.
+///
+/// Use [`set_cookie`](fn.set_cookie.html) to add it to a response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    expires: Option<SystemTime>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// A cookie with this name & value, and no other attributes
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Cookie {
+        Cookie {
+            name: name.into(),
+            value: value.into(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie which tells the client to delete the cookie with this name.
+    ///
+    /// The `Path` & `Domain` must match the ones the cookie was set with.
+    pub fn removal(name: impl Into<String>) -> Cookie {
+        Cookie::new(name, "")
+            .max_age(Duration::from_secs(0))
+            .expires(SystemTime::UNIX_EPOCH)
+    }
+
+    /// Set the `Path` attribute
+    pub fn path(mut self, path: impl Into<String>) -> Cookie {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Set the `Domain` attribute
+    pub fn domain(mut self, domain: impl Into<String>) -> Cookie {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    /// Set the `Max-Age` attribute (in whole seconds)
+    pub fn max_age(mut self, max_age: Duration) -> Cookie {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Set the `Expires` attribute
+    pub fn expires(mut self, expires: SystemTime) -> Cookie {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Set (or unset) the `Secure` attribute
+    pub fn secure(mut self, secure: bool) -> Cookie {
+        self.secure = secure;
+        self
+    }
+
+    /// Set (or unset) the `HttpOnly` attribute
+    pub fn http_only(mut self, http_only: bool) -> Cookie {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Set the `SameSite` attribute
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// The value for the `Set-Cookie` header.
+    ///
+    /// Fails if the name isn't a valid token, or the value or attributes contain characters which
+    /// aren't allowed (e.g. `;`, which could be used to inject other attributes).
+    pub fn header_value(&self) -> Result<http::HeaderValue, CgiError> {
+        let invalid = |what: &str| {
+            CgiError::InvalidResponseHeader(format!("Invalid cookie {}: {:?}", what, self.name))
+        };
+
+        let is_token = |c: u8| c.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&c);
+        if self.name.is_empty() || !self.name.bytes().all(is_token) {
+            return Err(invalid("name"));
+        }
+        // RFC 6265 § 4.1.1 cookie-octet
+        let is_cookie_octet = |c: u8| c.is_ascii_graphic() && !b"\",;\\".contains(&c);
+        if !self.value.bytes().all(is_cookie_octet) {
+            return Err(invalid("value"));
+        }
+        let is_attribute_value = |v: &String| v.bytes().all(|c| c.is_ascii_graphic() && c != b';');
+        if !self
+            .path
+            .iter()
+            .chain(self.domain.iter())
+            .all(is_attribute_value)
+        {
+            return Err(invalid("attribute"));
+        }
+
+        let mut header = format!("{}={}", self.name, self.value);
+        if let Some(path) = &self.path {
+            header.push_str("; Path=");
+            header.push_str(path);
+        }
+        if let Some(domain) = &self.domain {
+            header.push_str("; Domain=");
+            header.push_str(domain);
+        }
+        if let Some(max_age) = self.max_age {
+            header.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+        }
+        if let Some(expires) = self.expires {
+            header.push_str("; Expires=");
+            header.push_str(&http_date(expires));
+        }
+        if self.secure {
+            header.push_str("; Secure");
+        }
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => header.push_str("; SameSite=Strict"),
+            Some(SameSite::Lax) => header.push_str("; SameSite=Lax"),
+            Some(SameSite::None) => header.push_str("; SameSite=None"),
+            None => {}
+        }
+
+        http::HeaderValue::from_str(&header).map_err(|_| invalid("value"))
+    }
+}
+
+/// Add a `Set-Cookie` header for this cookie to the response. Any cookies already set are kept.
+pub fn set_cookie<B>(response: &mut http::Response<B>, cookie: &Cookie) -> Result<(), CgiError> {
+    response
+        .headers_mut()
+        .append(http::header::SET_COOKIE, cookie.header_value()?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookie_jar() {
+        let request = http::Request::builder()
+            .header(
+                "Cookie",
+                "session=abc123; theme=\"dark\"; empty=; =nameless",
+            )
+            .header("Cookie", "lang=en")
+            .body(())
+            .unwrap();
+        let jar = CookieJar::from_request(&request);
+
+        assert_eq!(jar.get("session"), Some("abc123"));
+        assert_eq!(jar.get("theme"), Some("dark"));
+        assert_eq!(jar.get("empty"), Some(""));
+        assert_eq!(jar.get("lang"), Some("en"));
+        assert_eq!(jar.get("missing"), None);
+        assert_eq!(jar.len(), 4);
+    }
+
+    #[test]
+    fn test_set_cookie_attributes() {
+        let cookie = Cookie::new("session", "abc123")
+            .path("/app")
+            .domain("example.com")
+            .max_age(Duration::from_secs(3600))
+            .expires(SystemTime::UNIX_EPOCH + Duration::from_secs(784111777))
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Lax);
+        assert_eq!(
+            cookie.header_value().unwrap(),
+            "session=abc123; Path=/app; Domain=example.com; Max-Age=3600; Expires=Sun, 06 Nov 1994 08:49:37 GMT; Secure; HttpOnly; SameSite=Lax"
+        );
+
+        assert_eq!(
+            Cookie::removal("session").header_value().unwrap(),
+            "session=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn test_invalid_cookies() {
+        assert!(Cookie::new("bad name", "x").header_value().is_err());
+        assert!(Cookie::new("", "x").header_value().is_err());
+        assert!(Cookie::new("a", "x; Domain=evil.com")
+            .header_value()
+            .is_err());
+        assert!(Cookie::new("a", "x")
+            .path("/; Secure")
+            .header_value()
+            .is_err());
+    }
+
+    #[test]
+    fn test_multiple_set_cookie() {
+        let mut response = crate::empty_response(200);
+        set_cookie(&mut response, &Cookie::new("a", "1")).unwrap();
+        set_cookie(&mut response, &Cookie::new("b", "2")).unwrap();
+
+        assert_eq!(
+            String::from_utf8(crate::serialize_response(response)).unwrap(),
+            "Status: 200 OK\nset-cookie: a=1\nset-cookie: b=2\n\n"
+        );
+    }
+}
@@ -0,0 +1,325 @@
+//! Running a CGI programme from the command line, without a webserver.
+//!
+//! When the first command line argument is `--cgi-debug`, or when `CGI_DEBUG` is set, the request
+//! is made from the (other) arguments instead of the environment, and the response is printed in
+//! a readable way:
+//!
+//! ```text
+//! $ ./my-script --cgi-debug POST '/path?q=1' -H 'Content-Type: text/plain' -d body.txt
+//! ```
+//!
+//! Otherwise the arguments are left alone, for the programme to use itself. When a webserver ran
+//! us (`GATEWAY_INTERFACE` or `REQUEST_METHOD` is set), debug mode is never used, as the
+//! webserver can pass the query string as arguments (RFC 3875 § 4.4), which would let any client
+//! make up the request.
+
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use crate::testing::TestRequest;
+
+const USAGE: &str = "\
+Usage: <programme> --cgi-debug [METHOD] URL [OPTIONS]
+       CGI_DEBUG=1 <programme> [METHOD] URL [OPTIONS]
+
+Run this CGI programme for one request, without a webserver, and print the response.
+
+  METHOD                 The request method (default: GET, or POST with --data)
+  URL                    The path (& query string) requested, e.g. /items?page=2
+  -H, --header 'N: V'    Add a request header (can be repeated)
+  -d, --data FILE        Send the contents of FILE as the request body (- for stdin)
+  -s, --script-name PATH The path the script is at, the rest of the URL is PATH_INFO
+  -e, --env NAME=VALUE   Set a meta-variable, e.g. REMOTE_USER=amanda (can be repeated)";
+
+/// The arguments describing the request, if debug mode was asked for, rather than running as a
+/// CGI programme. Never under a webserver.
+fn debug_args<'a>(env_vars: &HashMap<String, String>, args: &'a [String]) -> Option<&'a [String]> {
+    if env_vars.contains_key("GATEWAY_INTERFACE") || env_vars.contains_key("REQUEST_METHOD") {
+        return None;
+    }
+    match args.split_first() {
+        Some((first, rest)) if first == "--cgi-debug" => Some(rest),
+        _ => match env_vars.get("CGI_DEBUG") {
+            Some(debug) if !debug.is_empty() && debug != "0" => Some(args),
+            _ => None,
+        },
+    }
+}
+
+/// The request from the command line arguments, if we're in debug mode.
+///
+/// Exits with a usage message if the arguments can't be understood.
+pub(crate) fn request() -> Option<TestRequest> {
+    let env_vars = crate::env_vars();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match parse_args(debug_args(&env_vars, &args)?) {
+        Ok(request) => Some(request),
+        Err(msg) => {
+            eprintln!("{}\n\n{}", msg, USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<TestRequest, String> {
+    let mut positional = Vec::new();
+    let mut headers = Vec::new();
+    let mut vars = Vec::new();
+    let mut data = None;
+    let mut script_name = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Err("Debug mode".to_owned()),
+            "-H" | "--header" => {
+                let header = value()?;
+                let (name, value) = header
+                    .split_once(':')
+                    .ok_or_else(|| format!("Header {:?} isn't `Name: value`", header))?;
+                headers.push((name.trim().to_owned(), value.trim().to_owned()));
+            }
+            "-d" | "--data" => data = Some(value()?.clone()),
+            "-s" | "--script-name" => script_name = Some(value()?.clone()),
+            "-e" | "--env" => {
+                let var = value()?;
+                let (name, value) = var
+                    .split_once('=')
+                    .ok_or_else(|| format!("Meta-variable {:?} isn't `NAME=VALUE`", var))?;
+                vars.push((name.to_owned(), value.to_owned()));
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(format!("Unknown option {:?}", arg))
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    let (method, url) = match positional.as_slice() {
+        [url] if data.is_some() => ("POST".to_owned(), url.clone()),
+        [url] => ("GET".to_owned(), url.clone()),
+        [method, url] => (method.to_ascii_uppercase(), url.clone()),
+        [] => return Err("No URL given".to_owned()),
+        _ => return Err("Too many arguments".to_owned()),
+    };
+    if !url.starts_with('/') && !url.contains("://") {
+        return Err(format!("URL {:?} should start with a /", url));
+    }
+
+    let mut request = TestRequest::new(method, url);
+    if let Some(script_name) = script_name {
+        request = request.script_name(script_name);
+    }
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    for (name, value) in vars {
+        request = request.var(name, value);
+    }
+    if let Some(data) = data {
+        let body = if data == "-" {
+            let mut body = Vec::new();
+            std::io::Read::read_to_end(&mut std::io::stdin(), &mut body).map(|_| body)
+        } else {
+            std::fs::read(&data)
+        };
+        request = request.body(body.map_err(|err| format!("Can't read {:?}: {}", data, err))?);
+    }
+
+    Ok(request)
+}
+
+/// Run the request with `run` (which takes the meta-variables, stdin & stdout), then print the
+/// meta-variables to stderr and the response to stdout.
+pub(crate) fn run<F>(request: TestRequest, run: F)
+where
+    F: FnOnce(HashMap<String, String>, Cursor<Vec<u8>>, &mut Vec<u8>),
+{
+    let env_vars = request.env_vars();
+    let mut names: Vec<&String> = env_vars.keys().collect();
+    names.sort();
+    for name in names {
+        eprintln!("> {}={}", name, env_vars[name]);
+    }
+    eprintln!();
+
+    let mut output = Vec::new();
+    run(env_vars, Cursor::new(request.into_body()), &mut output);
+
+    crate::write_output(std::io::stdout(), pretty_output(&output).as_bytes());
+}
+
+/// The output, with the headers & body separated, and binary bodies summarised
+fn pretty_output(output: &[u8]) -> String {
+    // NPH output uses CRLF, CGI output uses LF
+    let crlf = output.windows(4).position(|w| w == b"\r\n\r\n");
+    let lf = output.windows(2).position(|w| w == b"\n\n");
+    let (head, body) = match (lf, crlf) {
+        (Some(lf), Some(crlf)) if crlf < lf => (&output[..crlf + 2], &output[crlf + 4..]),
+        (None, Some(crlf)) => (&output[..crlf + 2], &output[crlf + 4..]),
+        (Some(lf), _) => (&output[..lf + 1], &output[lf + 2..]),
+        (None, None) => (output, &b""[..]),
+    };
+
+    let mut pretty = String::from_utf8_lossy(head).replace("\r\n", "\n");
+    pretty.push('\n');
+    match std::str::from_utf8(body) {
+        Ok(body) => {
+            pretty.push_str(body);
+            if !body.is_empty() && !body.ends_with('\n') {
+                pretty.push('\n');
+            }
+        }
+        Err(_) => pretty.push_str(&format!("[{} bytes of binary data]\n", body.len())),
+    }
+    pretty
+}
+
+/// Exit if there's a body, as `handle_streaming_request` always reads the body from stdin
+pub(crate) fn no_body(request: &TestRequest) {
+    if request.env_vars().contains_key("CONTENT_LENGTH") {
+        eprintln!("handle_streaming_request reads the body from stdin, so --data can't be used");
+        std::process::exit(2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_debug_args() {
+        let env = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        // The programme's own arguments are left alone, with or without a webserver
+        assert_eq!(debug_args(&env(&[]), &args(&["/"])), None);
+        assert_eq!(debug_args(&env(&[]), &args(&[])), None);
+        assert_eq!(
+            debug_args(&env(&[]), &args(&["--verbose", "--cgi-debug"])),
+            None
+        );
+
+        assert_eq!(
+            debug_args(&env(&[]), &args(&["--cgi-debug", "/"])),
+            Some(&args(&["/"])[..])
+        );
+        assert_eq!(
+            debug_args(&env(&[("CGI_DEBUG", "1")]), &args(&["/"])),
+            Some(&args(&["/"])[..])
+        );
+        assert_eq!(
+            debug_args(&env(&[("CGI_DEBUG", "1")]), &args(&["--cgi-debug", "/"])),
+            Some(&args(&["/"])[..])
+        );
+        assert_eq!(debug_args(&env(&[("CGI_DEBUG", "0")]), &args(&["/"])), None);
+
+        // Never under a webserver
+        assert_eq!(
+            debug_args(
+                &env(&[("GATEWAY_INTERFACE", "CGI/1.1")]),
+                &args(&["--cgi-debug"])
+            ),
+            None
+        );
+        assert_eq!(
+            debug_args(
+                &env(&[("REQUEST_METHOD", "GET"), ("CGI_DEBUG", "1")]),
+                &args(&["/"])
+            ),
+            None
+        );
+        // An ISINDEX query, e.g. `?--cgi-debug+-e+REMOTE_USER%3Dadmin+-d+%2Fetc%2Fpasswd+%2F`,
+        // which Apache passes as the arguments
+        let isindex = args(&[
+            "--cgi-debug",
+            "-e",
+            "REMOTE_USER=admin",
+            "-d",
+            "/etc/passwd",
+            "/",
+        ]);
+        assert_eq!(
+            debug_args(
+                &env(&[
+                    ("GATEWAY_INTERFACE", "CGI/1.1"),
+                    ("REQUEST_METHOD", "GET"),
+                    (
+                        "QUERY_STRING",
+                        "--cgi-debug+-e+REMOTE_USER%3Dadmin+-d+%2Fetc%2Fpasswd+%2F"
+                    ),
+                ]),
+                &isindex
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let env_vars = parse_args(&args(&[
+            "delete",
+            "/app/items/3?force=1",
+            "-H",
+            "Accept: text/plain",
+            "-s",
+            "/app",
+            "--env",
+            "REMOTE_USER=amanda",
+        ]))
+        .unwrap()
+        .env_vars();
+        assert_eq!(env_vars["REQUEST_METHOD"], "DELETE");
+        assert_eq!(env_vars["SCRIPT_NAME"], "/app");
+        assert_eq!(env_vars["PATH_INFO"], "/items/3");
+        assert_eq!(env_vars["QUERY_STRING"], "force=1");
+        assert_eq!(env_vars["HTTP_ACCEPT"], "text/plain");
+        assert_eq!(env_vars["REMOTE_USER"], "amanda");
+
+        assert!(parse_args(&args(&[])).is_err());
+        assert!(parse_args(&args(&["items"])).is_err());
+        assert!(parse_args(&args(&["/", "-H", "no colon"])).is_err());
+        assert!(parse_args(&args(&["/", "-H"])).is_err());
+        assert!(parse_args(&args(&["/", "--bogus"])).is_err());
+        assert!(parse_args(&args(&["/", "-d", "/does/not/exist"])).is_err());
+    }
+
+    #[test]
+    fn test_data_is_post() {
+        let mut file = std::env::temp_dir();
+        file.push(format!("cgi-debug-test-{}", std::process::id()));
+        std::fs::write(&file, "a=1").unwrap();
+
+        let request = parse_args(&args(&["/form", "-d", file.to_str().unwrap()])).unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        let env_vars = request.env_vars();
+        assert_eq!(env_vars["REQUEST_METHOD"], "POST");
+        assert_eq!(env_vars["CONTENT_LENGTH"], "3");
+        assert_eq!(request.into_body(), b"a=1");
+    }
+
+    #[test]
+    fn test_pretty_output() {
+        assert_eq!(
+            pretty_output(b"Status: 200 OK\ncontent-type: text/plain\n\nHello"),
+            "Status: 200 OK\ncontent-type: text/plain\n\nHello\n"
+        );
+        assert_eq!(
+            pretty_output(b"HTTP/1.1 200 OK\r\ndate: x\r\n\r\na\n\nb\n"),
+            "HTTP/1.1 200 OK\ndate: x\n\na\n\nb\n"
+        );
+        assert_eq!(
+            pretty_output(b"Status: 200 OK\n\n\xff\xfe\x00"),
+            "Status: 200 OK\n\n[3 bytes of binary data]\n"
+        );
+    }
+}
@@ -0,0 +1,506 @@
+//! A small HTTP/1.1 server for trying out CGI programmes while developing.
+//!
+//! Requires the `dev-server` feature. Requests are turned into the same meta-variables a real
+//! webserver would set (with the path after `script_name` as `PATH_INFO`), and the response is
+//! handled like a webserver would, including local redirects.
+//!
+//! ```rust,no_run
+//! extern crate cgi;
+//!
+//! fn app(request: cgi::Request) -> cgi::Response {
+//!     cgi::text_response(200, format!("Hello from {}", request.uri()))
+//! }
+//!
+//! fn main() {
+//!     if std::env::var_os("GATEWAY_INTERFACE").is_some() {
+//!         cgi::handle(app);
+//!     } else {
+//!         // `cargo run`, then open http://127.0.0.1:8000/cgi-bin/app
+//!         let listener = std::net::TcpListener::bind("127.0.0.1:8000").unwrap();
+//!         cgi::dev_server::serve(listener, "/cgi-bin/app", app).unwrap();
+//!     }
+//! }
+//! ```
+//!
+//! This is not meant to be exposed to the internet. It closes the connection after every
+//! response, doesn't support chunked request bodies, and refuses bodies over 16 MiB.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+
+use crate::limits::read_exactly;
+use crate::testing::TestRequest;
+use crate::{
+    empty_response, error_response, handle_with_io, is_local_redirect, parse_response,
+    serialize_http_response, serve_incoming, CgiCommand, CgiError, Limits, Request, Response,
+};
+
+/// Refuse request heads bigger than this
+const MAX_HEAD_LENGTH: usize = 64 * 1024;
+
+/// Refuse request bodies bigger than this
+const MAX_BODY_LENGTH: u64 = 16 * 1024 * 1024;
+
+/// How many local redirects to follow, before deciding there's a loop
+const MAX_LOCAL_REDIRECTS: usize = 10;
+
+const SERVER_SOFTWARE: &str = concat!("rust-cgi-dev-server/", env!("CARGO_PKG_VERSION"));
+
+/// Runs one request, from the meta-variables & body, returning what the programme wrote to stdout
+type Dispatch = Box<dyn Fn(HashMap<String, String>, Vec<u8>) -> io::Result<Vec<u8>> + Send + Sync>;
+
+struct Server {
+    script_name: String,
+    dispatch: Dispatch,
+}
+
+/// Serve requests for `script_name` (e.g. `/cgi-bin/app`, or `""` for all paths) by calling
+/// `handler` in this process, forever.
+///
+/// Each request is passed through the same parsing & serialization as
+/// [`handle`](../fn.handle.html).
+pub fn serve<F>(listener: TcpListener, script_name: &str, handler: F) -> io::Result<()>
+where
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    let dispatch = move |env_vars, body| {
+        let mut output = Vec::new();
+        handle_with_io(
+            &handler,
+            env_vars,
+            Cursor::new(body),
+            &mut output,
+            &Limits::default(),
+        );
+        Ok(output)
+    };
+    serve_with(listener, script_name, Box::new(dispatch))
+}
+
+/// Serve requests for `script_name` by running the CGI programme at `program` for each one,
+/// forever.
+///
+/// The programme is run like [`CgiCommand`](../struct.CgiCommand.html) does.
+pub fn serve_command(
+    listener: TcpListener,
+    script_name: &str,
+    program: impl Into<PathBuf>,
+) -> io::Result<()> {
+    let command = CgiCommand::new(program);
+    let dispatch = move |env_vars, body: Vec<u8>| command.output(env_vars, &body);
+    serve_with(listener, script_name, Box::new(dispatch))
+}
+
+fn serve_with(listener: TcpListener, script_name: &str, dispatch: Dispatch) -> io::Result<()> {
+    let script_name = script_name.trim_end_matches('/').to_owned();
+    eprintln!("Serving http://{}{}/", listener.local_addr()?, script_name);
+    let server = Server {
+        script_name,
+        dispatch,
+    };
+    serve_incoming(listener.incoming(), server, handle_connection, "HTTP")
+}
+
+/// A request, as read from the connection
+#[derive(Debug)]
+struct HttpRequest {
+    method: String,
+    target: String,
+    version: http::Version,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// Read a request from the client. If it's not valid, `Err` has the response to send instead.
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Result<HttpRequest, Response>> {
+    let mut lines = Vec::new();
+    let mut head_length = 0;
+    loop {
+        let mut line = Vec::new();
+        let read = reader
+            .by_ref()
+            .take((MAX_HEAD_LENGTH - head_length) as u64)
+            .read_until(b'\n', &mut line)?;
+        head_length += read;
+        if !line.ends_with(b"\n") {
+            if head_length >= MAX_HEAD_LENGTH {
+                return Ok(Err(empty_response(431)));
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = String::from_utf8_lossy(&line).trim_end().to_owned();
+        if line.is_empty() {
+            // RFC 9112 § 2.2: ignore empty lines before the request line
+            if lines.is_empty() {
+                continue;
+            }
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut request_line = lines[0].split(' ');
+    let (method, target, version) = match (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Ok(Err(empty_response(400))),
+    };
+    let version = match version {
+        "HTTP/1.0" => http::Version::HTTP_10,
+        "HTTP/1.1" => http::Version::HTTP_11,
+        _ => return Ok(Err(empty_response(505))),
+    };
+
+    let mut headers = Vec::new();
+    for line in &lines[1..] {
+        match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() && !name.ends_with(' ') => {
+                headers.push((name.to_owned(), value.trim().to_owned()))
+            }
+            _ => return Ok(Err(empty_response(400))),
+        }
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    };
+
+    if header("Transfer-Encoding").is_some() {
+        return Ok(Err(empty_response(501)));
+    }
+    let content_length = match header("Content-Length").map(str::parse::<u64>) {
+        None => 0,
+        Some(Ok(content_length)) => content_length,
+        Some(Err(_)) => return Ok(Err(empty_response(400))),
+    };
+    let body = match Limits::new()
+        .max_body_size(MAX_BODY_LENGTH)
+        .check(content_length)
+        .and_then(|length| read_exactly(reader, length))
+    {
+        Ok(body) => body,
+        Err(CgiError::Io(err)) => return Err(err),
+        Err(err) => return Ok(Err(error_response(&err))),
+    };
+
+    Ok(Ok(HttpRequest {
+        method: method.to_owned(),
+        target: target.to_owned(),
+        version,
+        headers,
+        body,
+    }))
+}
+
+/// The meta-variables for this request, as a webserver would set them
+fn env_vars(
+    server: &Server,
+    request: &HttpRequest,
+    stream: &TcpStream,
+) -> io::Result<HashMap<String, String>> {
+    let mut test_request = TestRequest::new(request.method.as_str(), request.target.as_str())
+        .script_name(server.script_name.as_str())
+        .body(request.body.clone());
+    for (name, value) in &request.headers {
+        test_request = test_request.header(name.as_str(), value.as_str());
+    }
+
+    let local_addr = stream.local_addr()?;
+    let server_name = request
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Host"))
+        .map(|(_, host)| match host.rsplit_once(':') {
+            Some((name, port)) if !port.contains(']') => name.to_owned(),
+            _ => host.to_owned(),
+        })
+        .unwrap_or_else(|| local_addr.ip().to_string());
+
+    Ok(test_request
+        .remote_addr(stream.peer_addr()?.ip().to_string())
+        .var("SERVER_NAME", server_name)
+        .var("SERVER_PORT", local_addr.port().to_string())
+        .var(
+            "SERVER_PROTOCOL",
+            if request.version == http::Version::HTTP_10 {
+                "HTTP/1.0"
+            } else {
+                "HTTP/1.1"
+            },
+        )
+        .var("SERVER_SOFTWARE", SERVER_SOFTWARE)
+        .env_vars())
+}
+
+/// Whether this path is the script, or under it
+fn is_for_script(server: &Server, target: &str) -> bool {
+    let path = target.split('?').next().unwrap_or_default();
+    match path.strip_prefix(server.script_name.as_str()) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Run the request, following any local redirects
+fn respond(server: &Server, mut request: HttpRequest, stream: &TcpStream) -> io::Result<Response> {
+    for _ in 0..MAX_LOCAL_REDIRECTS {
+        if !is_for_script(server, &request.target) {
+            return Ok(empty_response(404));
+        }
+
+        let output = (server.dispatch)(env_vars(server, &request, stream)?, request.body)?;
+        let response = match parse_response(&output) {
+            Ok(response) => response,
+            Err(err) => {
+                eprintln!("Invalid output from the CGI programme: {}", err);
+                return Ok(empty_response(502));
+            }
+        };
+
+        if !is_local_redirect(&response) {
+            return Ok(response);
+        }
+
+        // RFC 3875 § 6.2.2: respond as if the client had requested the new path
+        request = HttpRequest {
+            method: "GET".to_owned(),
+            target: response.headers()[http::header::LOCATION]
+                .to_str()
+                .unwrap_or_default()
+                .to_owned(),
+            version: request.version,
+            headers: request
+                .headers
+                .into_iter()
+                .filter(|(name, _)| !name.to_ascii_lowercase().starts_with("content-"))
+                .collect(),
+            body: Vec::new(),
+        };
+    }
+
+    eprintln!("Too many local redirects");
+    Ok(empty_response(500))
+}
+
+fn handle_connection(stream: TcpStream, server: &Server) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+
+    let (response, method, version) = match read_request(&mut reader)? {
+        Ok(request) => {
+            eprintln!("{} {}", request.method, request.target);
+            let (method, version) = (request.method.clone(), request.version);
+            (respond(server, request, &stream)?, method, version)
+        }
+        Err(response) => (response, String::new(), http::Version::HTTP_11),
+    };
+    eprintln!("  -> {}", response.status());
+
+    let mut response = response;
+    response.headers_mut().insert(
+        http::header::CONNECTION,
+        http::HeaderValue::from_static("close"),
+    );
+    let mut output = serialize_http_response(
+        response,
+        version,
+        Some(SERVER_SOFTWARE),
+        std::time::SystemTime::now(),
+    );
+    if method == "HEAD" {
+        output = without_body(output);
+    }
+
+    stream.write_all(&output)?;
+    stream.flush()
+}
+
+/// Just the status line & headers of a serialized response. This is worked out from the output,
+/// as it may be an error response, not the one the handler returned.
+fn without_body(mut output: Vec<u8>) -> Vec<u8> {
+    if let Some(end) = output.windows(4).position(|w| w == b"\r\n\r\n") {
+        output.truncate(end + 4);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RequestExt;
+
+    /// Start a server on a free port, and return its address
+    fn start(serve: fn(TcpListener) -> io::Result<()>) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || serve(listener));
+        addr
+    }
+
+    fn send(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn app(request: Request) -> Response {
+        if request.uri().path() == "/app/moved" {
+            return crate::local_redirect("/app/here").unwrap();
+        }
+        let meta = request.cgi_meta().unwrap();
+        crate::text_response(
+            200,
+            format!(
+                "{} {} {:?} {} {}",
+                meta.request_method.as_deref().unwrap(),
+                meta.script_name.as_deref().unwrap(),
+                meta.path_info,
+                meta.server_name.as_deref().unwrap(),
+                String::from_utf8_lossy(request.body()),
+            ),
+        )
+    }
+
+    #[test]
+    fn test_serve() {
+        let addr = start(|listener| serve(listener, "/app", app));
+
+        let response = send(
+            addr,
+            "POST /app/a%20b?x=1 HTTP/1.1\r\nHost: example.com:8000\r\nContent-Length: 4\r\n\r\nbody",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\r\nconnection: close\r\n"));
+        assert!(
+            response.ends_with("\r\n\r\nPOST /app Some(\"/a b\") example.com body"),
+            "{}",
+            response
+        );
+
+        let response = send(addr, "GET /app/moved HTTP/1.0\r\nContent-Type: x\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{}", response);
+        assert!(response.contains("GET /app Some(\"/here\")"));
+
+        let response = send(addr, "HEAD /app HTTP/1.1\r\n\r\n");
+        assert!(
+            response.contains("\r\ncontent-length: 25\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.ends_with("\r\n\r\n"));
+
+        let response = send(addr, "GET /application HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let addr = start(|listener| serve(listener, "", app));
+
+        let response = send(addr, "GET / HTTP/2.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 505 "), "{}", response);
+
+        let response = send(addr, "GET /\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+
+        let response = send(addr, "GET / HTTP/1.1\r\nNo colon\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+
+        let response = send(
+            addr,
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 501 "), "{}", response);
+    }
+
+    #[test]
+    fn test_head_too_long() {
+        let head = format!(
+            "GET / HTTP/1.1\r\nX: {}\r\n\r\n",
+            "a".repeat(MAX_HEAD_LENGTH)
+        );
+        let response = read_request(&mut head.as_bytes()).unwrap().unwrap_err();
+        assert_eq!(response.status(), 431);
+    }
+
+    #[test]
+    fn test_body() {
+        let request = b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody, and more";
+        let request = read_request(&mut &request[..]).unwrap().unwrap();
+        assert_eq!(request.body, b"body");
+
+        let request = b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort";
+        let response = read_request(&mut &request[..]).unwrap().unwrap_err();
+        assert_eq!(response.status(), 400);
+
+        let request = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nbody",
+            MAX_BODY_LENGTH + 1
+        );
+        let response = read_request(&mut request.as_bytes()).unwrap().unwrap_err();
+        assert_eq!(response.status(), 413);
+    }
+
+    #[test]
+    fn test_without_body() {
+        let output = b"HTTP/1.1 200 OK\r\ncontent-length: 4\r\n\r\nbody".to_vec();
+        assert_eq!(
+            without_body(output),
+            b"HTTP/1.1 200 OK\r\ncontent-length: 4\r\n\r\n"
+        );
+
+        // A response which can't be serialized becomes an empty 500, which is shorter than the
+        // body the handler returned
+        let mut response = crate::local_redirect("/elsewhere").unwrap();
+        *response.body_mut() = vec![b'x'; 10_000];
+        let output = serialize_http_response(
+            response,
+            http::Version::HTTP_11,
+            None,
+            std::time::SystemTime::now(),
+        );
+        let output = String::from_utf8(without_body(output)).unwrap();
+        assert!(output.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(output.ends_with("\r\n\r\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_serve_command() {
+        let script = crate::test_util::Script::new(
+            "dev-server",
+            "printf 'Status: 201 Created\\nContent-Type: text/plain\\n\\n%s %s %s ' \"$REQUEST_METHOD\" \"$PATH_INFO\" \"$GATEWAY_INTERFACE\"\ncat\n",
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let program = script.path().to_owned();
+        std::thread::spawn(move || serve_command(listener, "/cgi-bin/test", program));
+
+        let response = send(
+            addr,
+            "PUT /cgi-bin/test/x HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+        );
+
+        assert!(
+            response.starts_with("HTTP/1.1 201 Created\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.ends_with("\r\n\r\nPUT /x CGI/1.1 hello"),
+            "{}",
+            response
+        );
+    }
+}
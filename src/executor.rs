@@ -0,0 +1,86 @@
+//! Running a `Future` to completion, for [`handle_async`](../fn.handle_async.html).
+
+use std::future::Future;
+
+/// Run the future on this thread, parking it while the future is waiting to be woken.
+///
+/// A CGI programme handles one request, so this doesn't need to be any cleverer than that.
+#[cfg(not(feature = "tokio"))]
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake};
+
+    struct ThreadWaker(std::thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+    let mut context = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            // Spurious wake ups just mean polling again
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+/// Run the future on a new tokio runtime, on this thread.
+#[cfg(feature = "tokio")]
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Unable to start the tokio runtime")
+        .block_on(future)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Pending the first time it's polled, and woken from another thread
+    struct WokenLater(bool);
+
+    impl Future for WokenLater {
+        type Output = &'static str;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            if self.0 {
+                return Poll::Ready("done");
+            }
+            self.0 = true;
+            let waker = cx.waker().clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                waker.wake();
+            });
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_block_on() {
+        assert_eq!(block_on(async { 1 + 1 }), 2);
+        assert_eq!(block_on(WokenLater(false)), "done");
+        assert_eq!(block_on(async { WokenLater(false).await.len() }), 4);
+    }
+
+    #[test]
+    fn test_async_handler() {
+        async fn app(request: crate::Request) -> crate::Response {
+            let greeting = WokenLater(false).await;
+            crate::text_response(200, format!("{} {}", greeting, request.uri().path()))
+        }
+
+        let response = crate::testing::TestRequest::get("/async").run(|r| block_on(app(r)));
+        assert_eq!(response.text(), "done /async");
+    }
+}
@@ -0,0 +1,617 @@
+//! Run a CGI handler as a persistent [FastCGI](https://fastcgi-archives.github.io/FastCGI_Specification.html)
+//! responder.
+//!
+//! The same `Fn(Request) -> Response` function you would pass to [`handle`](../fn.handle.html)
+//! can be served to a FastCGI webserver (e.g. nginx's `fastcgi_pass`). The `FCGI_PARAMS` of
+//! each request are used exactly like the CGI environmental variables are.
+//!
+//! The request body is received within the [`Limits`](../struct.Limits.html) you give, except
+//! for the read timeout, which is up to the webserver.
+//!
+//! ```rust,no_run
+//! extern crate cgi;
+//!
+//! fn main() {
+//!     let listener = std::net::TcpListener::bind("127.0.0.1:9000").unwrap();
+//!     let limits = cgi::Limits::new().max_body_size(16 * 1024 * 1024);
+//!     cgi::fastcgi::serve(
+//!         listener,
+//!         |request: cgi::Request| -> cgi::Response { cgi::text_response(200, "Hello World") },
+//!         &limits,
+//!     )
+//!     .unwrap();
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+
+use crate::{
+    error_response, parse_request, serialize_response, serve_incoming, CgiError, Limits, Request,
+    Response,
+};
+
+const VERSION_1: u8 = 1;
+
+const BEGIN_REQUEST: u8 = 1;
+const ABORT_REQUEST: u8 = 2;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const DATA: u8 = 8;
+const GET_VALUES: u8 = 9;
+const GET_VALUES_RESULT: u8 = 10;
+const UNKNOWN_TYPE: u8 = 11;
+
+const RESPONDER: u16 = 1;
+const KEEP_CONN: u8 = 1;
+
+const REQUEST_COMPLETE: u8 = 0;
+const UNKNOWN_ROLE: u8 = 3;
+
+/// Largest amount of content that fits in one record
+const MAX_CONTENT_LENGTH: usize = 0xffff;
+
+/// Refuse params bigger than this, rather than buffering whatever we're sent
+const MAX_PARAMS_LENGTH: usize = 1024 * 1024;
+
+/// A single FastCGI record
+#[derive(Debug, PartialEq)]
+struct Record {
+    record_type: u8,
+    request_id: u16,
+    content: Vec<u8>,
+}
+
+impl Record {
+    /// Read the next record. `None` if the connection was closed cleanly between records.
+    fn read<R: Read>(reader: &mut R) -> io::Result<Option<Record>> {
+        let mut header = [0; 8];
+        let mut filled = 0;
+        while filled < header.len() {
+            match reader.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if header[0] != VERSION_1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported FastCGI version {}", header[0]),
+            ));
+        }
+
+        let request_id = u16::from_be_bytes([header[2], header[3]]);
+        let content_length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let padding_length = header[6] as usize;
+
+        let mut content = vec![0; content_length + padding_length];
+        reader.read_exact(&mut content)?;
+        content.truncate(content_length);
+
+        Ok(Some(Record {
+            record_type: header[1],
+            request_id,
+            content,
+        }))
+    }
+
+    fn write<W: Write>(
+        writer: &mut W,
+        record_type: u8,
+        request_id: u16,
+        content: &[u8],
+    ) -> io::Result<()> {
+        debug_assert!(content.len() <= MAX_CONTENT_LENGTH);
+        // Keep records 8 byte aligned, as the spec recommends
+        let padding_length = (8 - content.len() % 8) % 8;
+        let id = request_id.to_be_bytes();
+        let len = (content.len() as u16).to_be_bytes();
+        writer.write_all(&[
+            VERSION_1,
+            record_type,
+            id[0],
+            id[1],
+            len[0],
+            len[1],
+            padding_length as u8,
+            0,
+        ])?;
+        writer.write_all(content)?;
+        writer.write_all(&[0; 8][..padding_length])
+    }
+}
+
+/// Decode a FastCGI name-value pair stream
+fn decode_name_values(mut bytes: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    fn length(bytes: &mut &[u8]) -> io::Result<usize> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Truncated FastCGI name-value pair",
+            )
+        };
+        let first = *bytes.first().ok_or_else(invalid)?;
+        if first & 0x80 == 0 {
+            *bytes = &bytes[1..];
+            Ok(first as usize)
+        } else {
+            if bytes.len() < 4 {
+                return Err(invalid());
+            }
+            let len = u32::from_be_bytes([first & 0x7f, bytes[1], bytes[2], bytes[3]]);
+            *bytes = &bytes[4..];
+            Ok(len as usize)
+        }
+    }
+
+    let mut pairs = Vec::new();
+    while !bytes.is_empty() {
+        let name_length = length(&mut bytes)?;
+        let value_length = length(&mut bytes)?;
+        if bytes.len() < name_length + value_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Truncated FastCGI name-value pair",
+            ));
+        }
+        let (name, rest) = bytes.split_at(name_length);
+        let (value, rest) = rest.split_at(value_length);
+        pairs.push((name.to_vec(), value.to_vec()));
+        bytes = rest;
+    }
+
+    Ok(pairs)
+}
+
+/// Encode name-value pairs, the inverse of `decode_name_values`
+fn encode_name_values(pairs: &[(&[u8], &[u8])]) -> Vec<u8> {
+    fn length(output: &mut Vec<u8>, len: usize) {
+        if len < 0x80 {
+            output.push(len as u8);
+        } else {
+            output.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+        }
+    }
+
+    let mut output = Vec::new();
+    for (name, value) in pairs {
+        length(&mut output, name.len());
+        length(&mut output, value.len());
+        output.extend_from_slice(name);
+        output.extend_from_slice(value);
+    }
+    output
+}
+
+/// A request which we have started to receive, but haven't responded to yet
+#[derive(Default)]
+struct PendingRequest {
+    keep_conn: bool,
+    params: Vec<u8>,
+    stdin: Vec<u8>,
+    /// How much stdin has been received, including any discarded for being too large
+    stdin_length: u64,
+}
+
+fn end_request<W: Write>(stream: &mut W, request_id: u16, protocol_status: u8) -> io::Result<()> {
+    Record::write(
+        stream,
+        END_REQUEST,
+        request_id,
+        &[0, 0, 0, 0, protocol_status, 0, 0, 0],
+    )?;
+    stream.flush()
+}
+
+fn respond<W, F>(
+    stream: &mut W,
+    request_id: u16,
+    pending: PendingRequest,
+    handler: &F,
+    limits: &Limits,
+) -> io::Result<()>
+where
+    W: Write,
+    F: Fn(Request) -> Response,
+{
+    let env_vars: HashMap<String, String> = decode_name_values(&pending.params)?
+        .into_iter()
+        .map(|(name, value)| {
+            (
+                String::from_utf8_lossy(&name).into_owned(),
+                String::from_utf8_lossy(&value).into_owned(),
+            )
+        })
+        .collect();
+
+    let received = pending.stdin_length;
+    let body = limits.check_length(&env_vars).and_then(|expected| {
+        limits.check(received)?;
+        if received < expected {
+            return Err(CgiError::IncompleteBody { expected, received });
+        }
+        Ok(pending.stdin)
+    });
+    let response = match body.and_then(|body| parse_request(env_vars, body)) {
+        Ok(request) => handler(request),
+        Err(err) => error_response(&err),
+    };
+
+    let output = serialize_response(response);
+
+    for chunk in output.chunks(MAX_CONTENT_LENGTH) {
+        Record::write(stream, STDOUT, request_id, chunk)?;
+    }
+    // An empty record marks the end of the stream
+    Record::write(stream, STDOUT, request_id, &[])?;
+
+    end_request(stream, request_id, REQUEST_COMPLETE)
+}
+
+/// Serve FastCGI requests from one connection to the webserver, until it closes it.
+///
+/// Requests can be multiplexed over the connection; each one is passed to `handler` once its
+/// params & stdin have been fully received. If the body is bigger than `limits` allow, the rest of
+/// it is discarded as it arrives, and an error response is sent instead, as it is if the body is
+/// shorter than `CONTENT_LENGTH`. This is useful if you are accepting connections yourself,
+/// otherwise see [`serve`](fn.serve.html).
+pub fn handle_connection<S, F>(mut stream: S, handler: &F, limits: &Limits) -> io::Result<()>
+where
+    S: Read + Write,
+    F: Fn(Request) -> Response,
+{
+    let mut requests: HashMap<u16, PendingRequest> = HashMap::new();
+
+    while let Some(record) = Record::read(&mut stream)? {
+        let request_id = record.request_id;
+        match record.record_type {
+            BEGIN_REQUEST => {
+                if record.content.len() < 3 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Truncated FCGI_BEGIN_REQUEST",
+                    ));
+                }
+                let role = u16::from_be_bytes([record.content[0], record.content[1]]);
+                if role != RESPONDER {
+                    end_request(&mut stream, request_id, UNKNOWN_ROLE)?;
+                    continue;
+                }
+                requests.insert(
+                    request_id,
+                    PendingRequest {
+                        keep_conn: record.content[2] & KEEP_CONN != 0,
+                        ..Default::default()
+                    },
+                );
+            }
+            ABORT_REQUEST => {
+                if let Some(pending) = requests.remove(&request_id) {
+                    end_request(&mut stream, request_id, REQUEST_COMPLETE)?;
+                    if !pending.keep_conn {
+                        return Ok(());
+                    }
+                }
+            }
+            PARAMS => {
+                if let Some(pending) = requests.get_mut(&request_id) {
+                    if pending.params.len() + record.content.len() > MAX_PARAMS_LENGTH {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "FastCGI params too long",
+                        ));
+                    }
+                    pending.params.extend_from_slice(&record.content);
+                }
+            }
+            STDIN => {
+                if let Some(pending) = requests.get_mut(&request_id) {
+                    if !record.content.is_empty() {
+                        pending.stdin_length += record.content.len() as u64;
+                        if limits.check(pending.stdin_length).is_ok() {
+                            pending.stdin.extend_from_slice(&record.content);
+                        } else {
+                            pending.stdin = Vec::new();
+                        }
+                        continue;
+                    }
+                    // An empty FCGI_STDIN means the request has been fully received
+                    let pending = requests.remove(&request_id).unwrap();
+                    let keep_conn = pending.keep_conn;
+                    respond(&mut stream, request_id, pending, handler, limits)?;
+                    if !keep_conn {
+                        return Ok(());
+                    }
+                }
+            }
+            // Only used by the filter role, which we don't support
+            DATA => {}
+            GET_VALUES => {
+                let mut values: Vec<(&[u8], &[u8])> = Vec::new();
+                for (name, _) in decode_name_values(&record.content)? {
+                    if name == b"FCGI_MPXS_CONNS" {
+                        values.push((b"FCGI_MPXS_CONNS", b"1"));
+                    }
+                }
+                Record::write(
+                    &mut stream,
+                    GET_VALUES_RESULT,
+                    0,
+                    &encode_name_values(&values),
+                )?;
+                stream.flush()?;
+            }
+            other if request_id == 0 => {
+                Record::write(&mut stream, UNKNOWN_TYPE, 0, &[other, 0, 0, 0, 0, 0, 0, 0])?;
+                stream.flush()?;
+            }
+            // Unknown application record types are ignored
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Accept FastCGI connections from `listener` forever, passing each request to `handler`.
+///
+/// Every connection is handled on its own thread, like
+/// [`handle_connection`](fn.handle_connection.html) does. Errors on one connection are printed to
+/// stderr, and don't stop the server.
+pub fn serve<F>(listener: TcpListener, handler: F, limits: &Limits) -> io::Result<()>
+where
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    serve_incoming(
+        listener.incoming(),
+        (handler, *limits),
+        |stream, (handler, limits)| handle_connection(stream, handler, limits),
+        "FastCGI",
+    )
+}
+
+/// Same as [`serve`](fn.serve.html), but for a Unix domain socket.
+#[cfg(unix)]
+pub fn serve_unix<F>(
+    listener: std::os::unix::net::UnixListener,
+    handler: F,
+    limits: &Limits,
+) -> io::Result<()>
+where
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    serve_incoming(
+        listener.incoming(),
+        (handler, *limits),
+        |stream, (handler, limits)| handle_connection(stream, handler, limits),
+        "FastCGI",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Duplex;
+
+    fn record(output: &mut Vec<u8>, record_type: u8, request_id: u16, content: &[u8]) {
+        Record::write(output, record_type, request_id, content).unwrap();
+    }
+
+    fn begin(output: &mut Vec<u8>, request_id: u16, flags: u8) {
+        record(
+            output,
+            BEGIN_REQUEST,
+            request_id,
+            &[0, 1, flags, 0, 0, 0, 0, 0],
+        );
+    }
+
+    fn params(output: &mut Vec<u8>, request_id: u16, params: &[(&str, &str)]) {
+        let pairs: Vec<(&[u8], &[u8])> = params
+            .iter()
+            .map(|(k, v)| (k.as_bytes(), v.as_bytes()))
+            .collect();
+        record(output, PARAMS, request_id, &encode_name_values(&pairs));
+        record(output, PARAMS, request_id, &[]);
+    }
+
+    fn run_within(input: Vec<u8>, limits: &Limits) -> io::Result<Vec<Record>> {
+        let mut conn = Duplex::new(input);
+        handle_connection(
+            &mut conn,
+            &|req: Request| {
+                crate::text_response(200, format!("{} {}", req.uri(), req.body().len()))
+            },
+            limits,
+        )?;
+
+        let mut output = io::Cursor::new(conn.output);
+        let mut records = Vec::new();
+        while let Some(record) = Record::read(&mut output).unwrap() {
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    fn run(input: Vec<u8>) -> Vec<Record> {
+        run_within(input, &Limits::default()).unwrap()
+    }
+
+    fn stdout_of(records: &[Record], request_id: u16) -> String {
+        let bytes: Vec<u8> = records
+            .iter()
+            .filter(|r| r.record_type == STDOUT && r.request_id == request_id)
+            .flat_map(|r| r.content.clone())
+            .collect();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_name_values() {
+        let long_value = "x".repeat(300);
+        let encoded =
+            encode_name_values(&[(b"SCRIPT_NAME", b"/app"), (b"LONG", long_value.as_bytes())]);
+        assert_eq!(
+            decode_name_values(&encoded).unwrap(),
+            vec![
+                (b"SCRIPT_NAME".to_vec(), b"/app".to_vec()),
+                (b"LONG".to_vec(), long_value.into_bytes()),
+            ]
+        );
+        assert!(decode_name_values(&[5, 5, b'a']).is_err());
+    }
+
+    #[test]
+    fn test_single_request() {
+        let mut input = Vec::new();
+        begin(&mut input, 1, 0);
+        params(
+            &mut input,
+            1,
+            &[("REQUEST_URI", "/hello"), ("CONTENT_LENGTH", "3")],
+        );
+        record(&mut input, STDIN, 1, b"abc");
+        record(&mut input, STDIN, 1, &[]);
+
+        let records = run(input);
+        assert_eq!(
+            stdout_of(&records, 1),
+            "Status: 200 OK\ncontent-length: 8\ncontent-type: text/plain; charset=utf-8\n\n/hello 3"
+        );
+        assert_eq!(
+            records.last().unwrap(),
+            &Record {
+                record_type: END_REQUEST,
+                request_id: 1,
+                content: vec![0, 0, 0, 0, REQUEST_COMPLETE, 0, 0, 0],
+            }
+        );
+    }
+
+    #[test]
+    fn test_multiplexed_requests() {
+        let mut input = Vec::new();
+        begin(&mut input, 1, KEEP_CONN);
+        begin(&mut input, 2, KEEP_CONN);
+        params(&mut input, 2, &[("REQUEST_URI", "/two")]);
+        params(&mut input, 1, &[("REQUEST_URI", "/one")]);
+        record(&mut input, STDIN, 1, b"1");
+        record(&mut input, STDIN, 2, &[]);
+        record(&mut input, STDIN, 1, &[]);
+
+        let records = run(input);
+        assert!(stdout_of(&records, 1).ends_with("\n\n/one 1"));
+        assert!(stdout_of(&records, 2).ends_with("\n\n/two 0"));
+
+        let ended: Vec<u16> = records
+            .iter()
+            .filter(|r| r.record_type == END_REQUEST)
+            .map(|r| r.request_id)
+            .collect();
+        assert_eq!(ended, vec![2, 1]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits::new().max_body_size(4);
+        let request = |request_id, content_length, stdin: &[&[u8]]| {
+            let mut input = Vec::new();
+            begin(&mut input, request_id, KEEP_CONN);
+            params(
+                &mut input,
+                request_id,
+                &[("CONTENT_LENGTH", content_length)],
+            );
+            for content in stdin {
+                record(&mut input, STDIN, request_id, content);
+            }
+            record(&mut input, STDIN, request_id, &[]);
+            input
+        };
+
+        let mut input = request(1, "4", &[b"ab", b"cd"]);
+        // A lying CONTENT_LENGTH doesn't get around the limit
+        input.extend(request(2, "4", &[b"abc", b"def"]));
+        input.extend(request(3, "5", &[b"abcde"]));
+        let records = run_within(input, &limits).unwrap();
+        assert!(stdout_of(&records, 1).ends_with("\n\n/ 4"));
+        assert_eq!(stdout_of(&records, 2), "Status: 413 Payload Too Large\n\n");
+        assert_eq!(stdout_of(&records, 3), "Status: 413 Payload Too Large\n\n");
+
+        // Body shorter than CONTENT_LENGTH
+        let records = run(request(1, "10", &[b"short"]));
+        assert_eq!(stdout_of(&records, 1), "Status: 400 Bad Request\n\n");
+
+        let mut input = Vec::new();
+        begin(&mut input, 1, 0);
+        let long_value = "x".repeat(MAX_CONTENT_LENGTH - 10);
+        let pairs: &[(&[u8], &[u8])] = &[(b"LONG", long_value.as_bytes())];
+        for _ in 0..MAX_PARAMS_LENGTH / long_value.len() + 1 {
+            record(&mut input, PARAMS, 1, &encode_name_values(pairs));
+        }
+        let err = run_within(input, &limits).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_management_records() {
+        let mut input = Vec::new();
+        record(
+            &mut input,
+            GET_VALUES,
+            0,
+            &encode_name_values(&[(b"FCGI_MPXS_CONNS", b""), (b"FCGI_MAX_REQS", b"")]),
+        );
+        record(&mut input, 42, 0, &[]);
+        // Authorizer role isn't supported
+        record(&mut input, BEGIN_REQUEST, 3, &[0, 2, 0, 0, 0, 0, 0, 0]);
+
+        let records = run(input);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].record_type, GET_VALUES_RESULT);
+        assert_eq!(
+            decode_name_values(&records[0].content).unwrap(),
+            vec![(b"FCGI_MPXS_CONNS".to_vec(), b"1".to_vec())]
+        );
+        assert_eq!(records[1].record_type, UNKNOWN_TYPE);
+        assert_eq!(records[1].content[0], 42);
+        assert_eq!(records[2].record_type, END_REQUEST);
+        assert_eq!(records[2].content[4], UNKNOWN_ROLE);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_socket_pair() {
+        let (mut webserver, app) = std::os::unix::net::UnixStream::pair().unwrap();
+        let thread = std::thread::spawn(move || {
+            handle_connection(
+                app,
+                &|_req: Request| crate::empty_response(204),
+                &Limits::default(),
+            )
+            .unwrap()
+        });
+
+        let mut input = Vec::new();
+        begin(&mut input, 7, 0);
+        params(&mut input, 7, &[]);
+        record(&mut input, STDIN, 7, &[]);
+        webserver.write_all(&input).unwrap();
+
+        let mut output = Vec::new();
+        webserver.read_to_end(&mut output).unwrap();
+        thread.join().unwrap();
+
+        let mut output = io::Cursor::new(output);
+        let first = Record::read(&mut output).unwrap().unwrap();
+        assert_eq!(first.request_id, 7);
+        assert_eq!(first.content, b"Status: 204 No Content\n\n");
+    }
+}
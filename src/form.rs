@@ -0,0 +1,284 @@
+use std::str::FromStr;
+
+use crate::meta::content_type;
+use crate::RequestExt;
+
+/// Fields from an HTML form, i.e. `application/x-www-form-urlencoded` data.
+///
+/// Fields keep the order they were sent in, and the same name can appear more than once (e.g.
+/// for `<select multiple>`).
+///
+/// ```rust
+/// extern crate cgi;
+///
+/// cgi::cgi_main! { |request: cgi::Request| -> cgi::Response {
+///     let form = cgi::Form::from_request(&request);
+///     let page: u32 = match form.get("page").unwrap_or(Ok(1)) {
+///         Ok(page) => page,
+///         Err(_) => return cgi::text_response(400, "Invalid page"),
+///     };
+///     cgi::text_response(200, format!("Hello {}, page {}", form.value("name").unwrap_or("world"), page))
+/// } }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Form {
+    fields: Vec<(String, String)>,
+}
+
+impl Form {
+    /// Decode `application/x-www-form-urlencoded` data, e.g. `name=J%C3%BCrgen&tag=a&tag=b`
+    pub fn parse(input: impl AsRef<[u8]>) -> Form {
+        let mut form = Form::default();
+        form.extend_from(input.as_ref());
+        form
+    }
+
+    /// The fields from the query string, followed by the fields in the request body.
+    ///
+    /// The body is only used if the request's `Content-Type` is
+    /// `application/x-www-form-urlencoded` (which is what a `<form method="post">` sends).
+    pub fn from_request<B: AsRef<[u8]>>(request: &http::Request<B>) -> Form {
+        let mut form = Form::default();
+
+        let query = request.uri().query().or_else(|| {
+            request
+                .cgi_meta()
+                .and_then(|meta| meta.query_string.as_deref())
+        });
+        if let Some(query) = query {
+            form.extend_from(query.as_bytes());
+        }
+
+        if is_urlencoded(request) {
+            form.extend_from(request.body().as_ref());
+        }
+
+        form
+    }
+
+    fn extend_from(&mut self, input: &[u8]) {
+        for pair in input.split(|b| *b == b'&').filter(|p| !p.is_empty()) {
+            let mut parts = pair.splitn(2, |b| *b == b'=');
+            let name = parts.next().unwrap_or_default();
+            let value = parts.next().unwrap_or_default();
+            self.fields.push((
+                String::from_utf8_lossy(&percent_decode(name)).into_owned(),
+                String::from_utf8_lossy(&percent_decode(value)).into_owned(),
+            ));
+        }
+    }
+
+    /// The (first) value of this field
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All the values of this field, in order
+    pub fn values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The (first) value of this field, parsed as a `T`.
+    ///
+    /// `None` if the field isn't present, `Some(Err(_))` if it can't be parsed.
+    pub fn get<T: FromStr>(&self, name: &str) -> Option<Result<T, T::Err>> {
+        self.value(name).map(str::parse)
+    }
+
+    /// Whether this field is present (even with an empty value)
+    pub fn contains(&self, name: &str) -> bool {
+        self.fields.iter().any(|(n, _)| n == name)
+    }
+
+    /// All the fields, as `(name, value)`, in order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// How many fields there are
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    /// Whether there are no fields
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+/// Does this request have a form as its body?
+fn is_urlencoded<B>(request: &http::Request<B>) -> bool {
+    match content_type(request) {
+        Some(content_type) => content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .eq_ignore_ascii_case("application/x-www-form-urlencoded"),
+        None => false,
+    }
+}
+
+/// Decode `%XX` escapes, and `+` as a space. Invalid escapes are left as they are.
+pub(crate) fn percent_decode(input: &[u8]) -> Vec<u8> {
+    decode(input, true)
+}
+
+/// Decode `%XX` escapes in a URL path, where `+` is just a `+`
+pub(crate) fn percent_decode_path(input: &[u8]) -> Vec<u8> {
+    decode(input, false)
+}
+
+/// Encode a decoded URL path (e.g. `PATH_INFO`) for use in a URI. `/` is kept as a separator,
+/// and everything which isn't allowed in a path segment is escaped, including `%`.
+pub(crate) fn percent_encode_path(input: &str) -> String {
+    encode(input, |_| false)
+}
+
+/// Encode a query string for use in a URI. Unlike paths, it's not decoded first, so `%` escapes
+/// (and `?`) are kept, and only bytes which aren't allowed at all are escaped.
+pub(crate) fn percent_encode_query(input: &str) -> String {
+    encode(input, |b| b == b'%' || b == b'?')
+}
+
+/// Escape everything which isn't allowed in a path segment, except `/` and what `keep` says
+fn encode(input: &str, keep: fn(u8) -> bool) -> String {
+    let mut output = String::with_capacity(input.len());
+    for b in input.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => output.push(b as char),
+            b'-' | b'.' | b'_' | b'~' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+'
+            | b',' | b';' | b'=' | b':' | b'@' | b'/' => output.push(b as char),
+            b if keep(b) => output.push(b as char),
+            b => output.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    output
+}
+
+fn decode(input: &[u8], plus_as_space: bool) -> Vec<u8> {
+    fn hex(b: u8) -> Option<u8> {
+        (b as char).to_digit(16).map(|d| d as u8)
+    }
+
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'+' if plus_as_space => output.push(b' '),
+            b'%' => match (
+                input.get(i + 1).and_then(|b| hex(*b)),
+                input.get(i + 2).and_then(|b| hex(*b)),
+            ) {
+                (Some(high), Some(low)) => {
+                    output.push(high * 16 + low);
+                    i += 2;
+                }
+                _ => output.push(b'%'),
+            },
+            b => output.push(b),
+        }
+        i += 1;
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode(b"a+b%20c"), b"a b c");
+        assert_eq!(percent_decode(b"J%C3%BCrgen"), "Jürgen".as_bytes());
+        assert_eq!(percent_decode(b"100%"), b"100%");
+        assert_eq!(percent_decode(b"%zz%4"), b"%zz%4");
+        assert_eq!(percent_decode(b"%2B"), b"+");
+        assert_eq!(percent_decode_path(b"/a+b%20c"), b"/a+b c");
+    }
+
+    #[test]
+    fn test_percent_encode_path() {
+        assert_eq!(percent_encode_path("/a b/Jürgen"), "/a%20b/J%C3%BCrgen");
+        assert_eq!(percent_encode_path("/100%?#"), "/100%25%3F%23");
+        assert_eq!(percent_encode_path("/a+b:c@d;e=f"), "/a+b:c@d;e=f");
+        assert_eq!(
+            percent_decode_path(percent_encode_path("/x%2Fy z").as_bytes()),
+            b"/x%2Fy z"
+        );
+        assert_eq!(
+            percent_encode_query("a=1%202&b=x y?\"ü\n"),
+            "a=1%202&b=x%20y?%22%C3%BC%0A"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let form = Form::parse("name=J%C3%BCrgen&tag=a&&tag=b+c&empty=&flag");
+        assert_eq!(form.value("name"), Some("Jürgen"));
+        assert_eq!(form.values("tag").collect::<Vec<_>>(), vec!["a", "b c"]);
+        assert_eq!(form.value("empty"), Some(""));
+        assert!(form.contains("flag"));
+        assert!(!form.contains("missing"));
+        assert_eq!(form.len(), 5);
+    }
+
+    #[test]
+    fn test_get() {
+        let form = Form::parse("page=3&size=big");
+        assert_eq!(form.get::<u32>("page"), Some(Ok(3)));
+        assert!(form.get::<u32>("size").unwrap().is_err());
+        assert_eq!(form.get::<u32>("missing"), None);
+    }
+
+    #[test]
+    fn test_from_request() {
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("/script?a=1&b=2")
+            .header(
+                "Content-Type",
+                "application/x-www-form-urlencoded; charset=UTF-8",
+            )
+            .body(b"b=3&c=4".to_vec())
+            .unwrap();
+        let form = Form::from_request(&request);
+        assert_eq!(
+            form.iter().collect::<Vec<_>>(),
+            vec![("a", "1"), ("b", "2"), ("b", "3"), ("c", "4")]
+        );
+
+        // Only form bodies are decoded
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("/script")
+            .header("Content-Type", "text/plain")
+            .body(b"b=3&c=4".to_vec())
+            .unwrap();
+        assert!(Form::from_request(&request).is_empty());
+    }
+
+    #[test]
+    fn test_from_cgi_request() {
+        let env_vars = vec![
+            ("REQUEST_METHOD", "POST"),
+            ("CONTENT_TYPE", "application/x-www-form-urlencoded"),
+            ("SCRIPT_NAME", "/script"),
+            ("QUERY_STRING", "q=rust"),
+        ]
+        .into_iter()
+        .map(|(a, b)| (a.to_owned(), b.to_owned()))
+        .collect();
+        let request = crate::parse_request(env_vars, b"lang=en".to_vec()).unwrap();
+
+        let form = Form::from_request(&request);
+        assert_eq!(form.value("q"), Some("rust"));
+        assert_eq!(form.value("lang"), Some("en"));
+    }
+}
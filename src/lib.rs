@@ -48,6 +48,32 @@
 //! })}
 //! ```
 //!
+//! The CGI meta-variables (e.g. `REMOTE_ADDR`, `PATH_INFO`) are available in typed form with
+//! [`RequestExt::cgi_meta`](trait.RequestExt.html#tymethod.cgi_meta):
+//!
+//! ```rust
+//! extern crate cgi;
+//! use cgi::RequestExt;
+//!
+//! cgi::cgi_main! { |request: cgi::Request| -> cgi::Response {
+//!     let meta = request.cgi_meta().unwrap();
+//!     cgi::text_response(200, format!("Hello {:?}", meta.remote_addr))
+//! } }
+//! ```
+//!
+//! To send requests to different functions, depending on the method & path, pass a
+//! [`Router`](struct.Router.html)'s [`into_handler`](struct.Router.html#method.into_handler) to
+//! `cgi_main!` or `cgi::handle`:
+//!
+//! ```rust
+//! extern crate cgi;
+//!
+//! cgi::cgi_main! { cgi::Router::new()
+//!     .get("/", |_| cgi::text_response(200, "Home"))
+//!     .into_handler()
+//! }
+//! ```
+//!
 //! Several shortcut functions are provided (such as [`html_response`](fn.html_response.html)/[`binary_response`](fn.binary_response.html))
 
 use std::collections::HashMap;
@@ -57,44 +83,360 @@ use std::io::{Read, Write};
 
 pub extern crate http;
 
+mod command;
+mod cookie;
+mod debug;
+#[cfg(feature = "dev-server")]
+pub mod dev_server;
+mod executor;
+pub mod fastcgi;
+mod form;
+mod limits;
+mod meta;
+pub mod multipart;
+mod proxy;
+mod router;
+pub mod scgi;
+#[cfg(feature = "tower")]
+mod service;
+#[cfg(test)]
+mod test_util;
+pub mod testing;
+
+pub use command::CgiCommand;
+pub use cookie::{set_cookie, Cookie, CookieJar, SameSite};
+pub use form::Form;
+pub use limits::Limits;
+pub use meta::{CgiMeta, RequestExt};
+pub use proxy::{ClientInfo, TrustedProxies};
+pub use router::Router;
+#[cfg(feature = "tower")]
+pub use service::{handle_service, ServiceBody};
+
 /// A `Vec<u8>` Request from http
 pub type Request = http::Request<Vec<u8>>;
 
 /// A `Vec<u8>` Response from http
 pub type Response = http::Response<Vec<u8>>;
 
-fn handle_with_io<F, R, W>(func: F, mut stdin: R, mut stdout: W)
-where
+/// Errors which can happen while turning the CGI environment & stdin into a `Request`, or while
+/// parsing the request body
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CgiError {
+    /// Reading the request body (or writing a temporary file) failed
+    Io(std::io::Error),
+    /// `SERVER_PROTOCOL` isn't a version of HTTP we know about
+    UnsupportedProtocol(String),
+    /// The CGI variables don't make a valid `Request`, e.g. an invalid `REQUEST_URI`, or a header
+    /// value with invalid bytes
+    InvalidRequest(http::Error),
+    /// The request body isn't valid `multipart/form-data`
+    InvalidMultipart(String),
+    /// A header for the response couldn't be created, e.g. a cookie with invalid characters
+    InvalidResponseHeader(String),
+    /// The output of a CGI programme isn't a valid CGI response
+    InvalidResponse(String),
+    /// The request body (`CONTENT_LENGTH`) is larger than the maximum size, see
+    /// [`Limits`](struct.Limits.html)
+    BodyTooLarge {
+        /// The `CONTENT_LENGTH`
+        length: u64,
+        /// The maximum size
+        limit: u64,
+    },
+    /// The request body ended before `CONTENT_LENGTH` bytes were read
+    IncompleteBody {
+        /// The `CONTENT_LENGTH`
+        expected: u64,
+        /// How many bytes there were
+        received: u64,
+    },
+    /// The request body wasn't read before the read timeout, see [`Limits`](struct.Limits.html)
+    Timeout(std::time::Duration),
+}
+
+impl CgiError {
+    /// The HTTP status code which should be returned to the client for this error
+    pub fn status(&self) -> http::StatusCode {
+        match self {
+            CgiError::Io(_) => http::StatusCode::INTERNAL_SERVER_ERROR,
+            CgiError::UnsupportedProtocol(_) => http::StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            CgiError::InvalidRequest(_) => http::StatusCode::BAD_REQUEST,
+            CgiError::InvalidMultipart(_) => http::StatusCode::BAD_REQUEST,
+            CgiError::InvalidResponseHeader(_) => http::StatusCode::INTERNAL_SERVER_ERROR,
+            CgiError::InvalidResponse(_) => http::StatusCode::BAD_GATEWAY,
+            CgiError::BodyTooLarge { .. } => http::StatusCode::PAYLOAD_TOO_LARGE,
+            CgiError::IncompleteBody { .. } => http::StatusCode::BAD_REQUEST,
+            CgiError::Timeout(_) => http::StatusCode::REQUEST_TIMEOUT,
+        }
+    }
+}
+
+impl std::fmt::Display for CgiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CgiError::Io(err) => write!(f, "I/O error: {}", err),
+            CgiError::UnsupportedProtocol(protocol) => {
+                write!(f, "Unsupported HTTP SERVER_PROTOCOL {:?}", protocol)
+            }
+            CgiError::InvalidRequest(err) => write!(f, "Invalid request: {}", err),
+            CgiError::InvalidMultipart(msg) => write!(f, "Invalid multipart body: {}", msg),
+            CgiError::InvalidResponseHeader(msg) => write!(f, "Invalid response header: {}", msg),
+            CgiError::InvalidResponse(msg) => write!(f, "Invalid CGI response: {}", msg),
+            CgiError::BodyTooLarge { length, limit } => write!(
+                f,
+                "Request body of {} bytes is larger than the limit of {} bytes",
+                length, limit
+            ),
+            CgiError::IncompleteBody { expected, received } => write!(
+                f,
+                "Request body ended after {} of {} bytes",
+                received, expected
+            ),
+            CgiError::Timeout(timeout) => {
+                write!(f, "Request body wasn't read within {:?}", timeout)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CgiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CgiError::Io(err) => Some(err),
+            CgiError::UnsupportedProtocol(_) => None,
+            CgiError::InvalidRequest(err) => Some(err),
+            CgiError::InvalidMultipart(_) => None,
+            CgiError::InvalidResponseHeader(_) => None,
+            CgiError::InvalidResponse(_) => None,
+            CgiError::BodyTooLarge { .. } => None,
+            CgiError::IncompleteBody { .. } => None,
+            CgiError::Timeout(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for CgiError {
+    fn from(err: std::io::Error) -> Self {
+        CgiError::Io(err)
+    }
+}
+
+impl From<http::Error> for CgiError {
+    fn from(err: http::Error) -> Self {
+        CgiError::InvalidRequest(err)
+    }
+}
+
+/// The response sent when the request couldn't be parsed. The error is logged to stderr.
+fn error_response(err: &CgiError) -> Response {
+    eprintln!("{}", err);
+    empty_response(err.status())
+}
+
+/// A Request whose body is read lazily from stdin, see
+/// [`handle_streaming_request`](fn.handle_streaming_request.html)
+pub type StreamingRequest = http::Request<RequestBody>;
+
+/// The body of a [`StreamingRequest`](type.StreamingRequest.html).
+///
+/// Reads from stdin (or `R`), but never more than the `CONTENT_LENGTH` the webserver gave us,
+/// so reading to the end won't block waiting for data that will never come. If stdin ends before
+/// then, reading fails with an `UnexpectedEof` error.
+pub struct RequestBody<R = std::io::Stdin> {
+    inner: std::io::Take<R>,
+}
+
+impl<R: Read> RequestBody<R> {
+    fn new(reader: R, content_length: u64) -> Self {
+        RequestBody {
+            inner: reader.take(content_length),
+        }
+    }
+
+    /// How many bytes of the body are left to be read
+    pub fn remaining(&self) -> u64 {
+        self.inner.limit()
+    }
+}
+
+impl<R: Read> Read for RequestBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 && !buf.is_empty() && self.inner.limit() > 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Request body ended with {} bytes still to come",
+                    self.inner.limit()
+                ),
+            ));
+        }
+        Ok(read)
+    }
+}
+
+impl<R> Debug for RequestBody<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestBody")
+            .field("remaining", &self.inner.limit())
+            .finish()
+    }
+}
+
+/// How many bytes we have to read for request body
+fn content_length(env_vars: &HashMap<String, String>) -> usize {
+    env_vars
+        .get("CONTENT_LENGTH")
+        .and_then(|cl| cl.parse::<usize>().ok())
+        .unwrap_or(0)
+}
+
+fn read_request<R: Read + Send + 'static>(
+    env_vars: HashMap<String, String>,
+    stdin: R,
+    limits: &Limits,
+) -> Result<Request, CgiError> {
+    let body = limits.read_body(&env_vars, stdin)?;
+
+    parse_request(env_vars, body)
+}
+
+/// Write the output to stdout. There's no one to tell if this fails (the client has probably gone
+/// away), so just log it.
+fn write_output<W: Write>(mut stdout: W, output: &[u8]) {
+    if let Err(err) = stdout.write_all(output).and_then(|_| stdout.flush()) {
+        eprintln!("Unable to write response: {}", err);
+    }
+}
+
+fn handle_with_io<F, R, W>(
+    func: F,
+    env_vars: HashMap<String, String>,
+    stdin: R,
+    stdout: W,
+    limits: &Limits,
+) where
     F: FnOnce(Request) -> Response,
-    R: Read,
+    R: Read + Send + 'static,
     W: Write,
 {
-    let env_vars: HashMap<String, String> = std::env::vars().collect();
+    let response = match read_request(env_vars, stdin, limits) {
+        Ok(request) => func(request),
+        Err(err) => error_response(&err),
+    };
 
-    // How many bytes do we have to read for request body
-    // A general stdin().read_to_end() can block if the webserver doesn't close things
-    let content_length: usize = env_vars
-        .get("CONTENT_LENGTH")
-        .and_then(|cl| cl.parse::<usize>().ok())
-        .unwrap_or(0);
+    let output = serialize_response(response);
+
+    write_output(stdout, &output);
+}
 
-    let mut stdin_contents = vec![0; content_length];
-    stdin.read_exact(&mut stdin_contents).unwrap();
+fn handle_streaming_with_io<F, B, R, W>(
+    func: F,
+    env_vars: HashMap<String, String>,
+    stdin: R,
+    mut stdout: W,
+    limits: &Limits,
+) where
+    F: FnOnce(Request) -> http::Response<B>,
+    B: Read,
+    R: Read + Send + 'static,
+    W: Write,
+{
+    let request = match read_request(env_vars, stdin, limits) {
+        Ok(request) => request,
+        Err(err) => return write_output(stdout, &serialize_response(error_response(&err))),
+    };
 
-    let request = parse_request(env_vars, stdin_contents);
+    let (parts, mut body) = func(request).into_parts();
 
-    let response = func(request);
+    let head = match serialize_head(&parts) {
+        Ok(head) => head,
+        Err(err) => return write_output(stdout, &serialize_response(error_response(&err))),
+    };
+
+    if is_local_redirect_parts(&parts) {
+        return write_output(stdout, &head);
+    }
+
+    // Headers go out first, then the body is copied across without being buffered in memory
+    let result = stdout
+        .write_all(&head)
+        .and_then(|_| std::io::copy(&mut body, &mut stdout))
+        .and_then(|_| stdout.flush());
+    if let Err(err) = result {
+        eprintln!("Unable to write response: {}", err);
+    }
+}
+
+fn handle_streaming_request_with_io<F, R, W>(
+    func: F,
+    env_vars: HashMap<String, String>,
+    stdin: R,
+    stdout: W,
+    limits: &Limits,
+) where
+    F: FnOnce(http::Request<RequestBody<R>>) -> Response,
+    R: Read,
+    W: Write,
+{
+    let response = match limits
+        .check_length(&env_vars)
+        .and_then(|length| parse_request(env_vars, RequestBody::new(stdin, length)))
+    {
+        Ok(request) => func(request),
+        Err(err) => error_response(&err),
+    };
 
     let output = serialize_response(response);
 
-    stdout.write_all(&output).unwrap();
+    write_output(stdout, &output);
 }
 
-fn try_handle_with_io<E, F, R, W, X>(func: F, stdin: R, stdout: W, mut stderr: X)
-where
+fn handle_nph_with_io<F, R, W>(
+    func: F,
+    env_vars: HashMap<String, String>,
+    stdin: R,
+    stdout: W,
+    limits: &Limits,
+) where
+    F: FnOnce(Request) -> Response,
+    R: Read + Send + 'static,
+    W: Write,
+{
+    let (response, version, server) = match read_request(env_vars, stdin, limits) {
+        Ok(request) => {
+            let version = request.version();
+            let server = request
+                .cgi_meta()
+                .and_then(|meta| meta.server_software.clone());
+            (func(request), version, server)
+        }
+        Err(err) => (error_response(&err), http::Version::HTTP_11, None),
+    };
+
+    let output = serialize_http_response(
+        response,
+        version,
+        server.as_deref(),
+        std::time::SystemTime::now(),
+    );
+
+    write_output(stdout, &output);
+}
+
+fn try_handle_with_io<E, F, R, W, X>(
+    func: F,
+    env_vars: HashMap<String, String>,
+    stdin: R,
+    stdout: W,
+    mut stderr: X,
+    limits: &Limits,
+) where
     E: Debug,
     F: FnOnce(Request) -> Result<Response, E>,
-    R: Read,
+    R: Read + Send + 'static,
     W: Write,
     X: Write,
 {
@@ -106,22 +448,68 @@ where
                 empty_response(500)
             }
         },
+        env_vars,
         stdin,
         stdout,
+        limits,
     )
 }
 
+/// Accept connections forever for the persistent protocols (FastCGI, SCGI, the development
+/// server), handling each one on its own thread with `handle_connection`.
+fn serve_incoming<I, S, F>(
+    incoming: I,
+    handler: F,
+    handle_connection: fn(S, &F) -> std::io::Result<()>,
+    protocol: &'static str,
+) -> std::io::Result<()>
+where
+    I: Iterator<Item = std::io::Result<S>>,
+    S: Read + Write + Send + 'static,
+    F: Send + Sync + 'static,
+{
+    let handler = std::sync::Arc::new(handler);
+    for stream in incoming {
+        let stream = stream?;
+        let handler = std::sync::Arc::clone(&handler);
+        std::thread::spawn(move || {
+            if let Err(err) = handle_connection(stream, &*handler) {
+                eprintln!("{} connection error: {}", protocol, err);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// The CGI meta-variables (and `HTTP_*` headers) the webserver gave us
+fn env_vars() -> HashMap<String, String> {
+    std::env::vars().collect()
+}
+
 /// Call a function as a CGI programme.
 ///
 /// This should be called from a `main` function.
 /// Parse & extract the CGI environmental variables, and HTTP request body,
 /// to create `Request`, and convert your `Response` into the correct format and
 /// print to stdout.
+///
+/// If the request can't be parsed (see [`CgiError`](enum.CgiError.html)), `func` isn't called,
+/// the error is written to stderr, and an empty response with the appropriate status code (e.g.
+/// `400 Bad Request`) is sent instead.
+///
+/// For debugging, the request can be given on the command line instead, after a `--cgi-debug`
+/// argument, e.g. `./my-script --cgi-debug POST /items -H 'Content-Type: text/plain' -d body.txt`
+/// (or set `CGI_DEBUG=1` and leave it out). The response is printed to stdout. Run with
+/// `--cgi-debug --help` for all the options. Otherwise the command line isn't looked at, and it
+/// never is when run by a webserver.
+///
+/// The whole request body is read, however large it is; use [`Limits`](struct.Limits.html) to
+/// limit its size, and how long reading it can take.
 pub fn handle<F>(func: F)
 where
     F: FnOnce(Request) -> Response,
 {
-    handle_with_io(func, std::io::stdin(), std::io::stdout())
+    Limits::default().handle(func)
 }
 
 /// Call a function as a CGI programme.
@@ -134,7 +522,88 @@ where
     E: Debug,
     F: FnOnce(Request) -> Result<Response, E>,
 {
-    try_handle_with_io(func, std::io::stdin(), std::io::stdout(), std::io::stderr())
+    Limits::default().try_handle(func)
+}
+
+/// Call a function as a CGI programme, streaming the response body to stdout.
+///
+/// Same as `handle`, but the response body can be anything that implements `Read` (e.g. a
+/// `File`, or a pipe from a child process). The headers are written first, then the body is
+/// copied straight to stdout, so large responses never need to be held in memory.
+///
+/// ```rust,ignore
+/// extern crate cgi;
+///
+/// fn main() { cgi::handle_streaming(|request: cgi::Request| {
+///     let file = std::fs::File::open("export.csv").unwrap();
+///     cgi::streaming_response(200, "text/csv", file)
+/// })}
+/// ```
+pub fn handle_streaming<F, B>(func: F)
+where
+    F: FnOnce(Request) -> http::Response<B>,
+    B: Read,
+{
+    Limits::default().handle_streaming(func)
+}
+
+/// Call a function as a CGI programme, without reading the request body up front.
+///
+/// Same as `handle`, but the request body is a [`RequestBody`](struct.RequestBody.html) which
+/// reads from stdin as the function asks for it (up to `CONTENT_LENGTH` bytes), so large uploads
+/// can be streamed to disk or hashed incrementally.
+///
+/// ```rust,ignore
+/// extern crate cgi;
+///
+/// fn main() { cgi::handle_streaming_request(|mut request: cgi::StreamingRequest| {
+///     let mut file = std::fs::File::create("upload.bin").unwrap();
+///     std::io::copy(request.body_mut(), &mut file).unwrap();
+///     cgi::empty_response(201)
+/// })}
+/// ```
+pub fn handle_streaming_request<F>(func: F)
+where
+    F: FnOnce(StreamingRequest) -> Response,
+{
+    Limits::default().handle_streaming_request(func)
+}
+
+/// Call a function as a Non-Parsed Header (NPH) CGI programme.
+///
+/// Same as `handle`, but the response is written as a complete HTTP/1.x response (status line
+/// and all headers), which the webserver sends to the client unchanged (RFC 3875 § 5.1). The
+/// HTTP version is taken from `SERVER_PROTOCOL`. `Date`, `Server` & `Content-Length` headers are
+/// added if the response doesn't already have them.
+///
+/// Webservers usually only treat scripts whose name starts with `nph-` like this.
+pub fn handle_nph<F>(func: F)
+where
+    F: FnOnce(Request) -> Response,
+{
+    Limits::default().handle_nph(func)
+}
+
+/// Call an `async` function as a CGI programme.
+///
+/// Same as `handle`, but `func` returns a `Future`, which is run to completion on a minimal
+/// executor on this thread. With the `tokio` feature, it's run on a (current thread) tokio
+/// runtime instead, for libraries which need one.
+///
+/// ```rust,ignore
+/// extern crate cgi;
+///
+/// fn main() { cgi::handle_async(|request: cgi::Request| async move {
+///     let name = lookup_user(&request).await;
+///     cgi::text_response(200, format!("Hello {}", name))
+/// })}
+/// ```
+pub fn handle_async<F, Fut>(func: F)
+where
+    F: FnOnce(Request) -> Fut,
+    Fut: std::future::Future<Output = Response>,
+{
+    Limits::default().handle_async(func)
 }
 
 #[macro_export]
@@ -184,6 +653,93 @@ macro_rules! cgi_try_main {
     };
 }
 
+#[macro_export]
+/// Create a `main` function for a CGI script which streams its response body
+///
+/// Same as [`cgi_main!`](macro.cgi_main.html), but the function can return a response whose
+/// body is any `Read`, see [`handle_streaming`](fn.handle_streaming.html).
+///
+/// ```rust
+/// extern crate cgi;
+///
+/// cgi::cgi_streaming_main! { |request: cgi::Request| {
+///     use std::io::Read;
+///     cgi::streaming_response(200, "text/plain", std::io::repeat(b'a').take(1024))
+/// } }
+/// ```
+macro_rules! cgi_streaming_main {
+    ( $func:expr ) => {
+        fn main() {
+            cgi::handle_streaming($func);
+        }
+    };
+}
+
+#[macro_export]
+/// Create a `main` function for a CGI script which reads the request body lazily
+///
+/// Same as [`cgi_main!`](macro.cgi_main.html), but the function takes a
+/// [`cgi::StreamingRequest`](type.StreamingRequest.html), see
+/// [`handle_streaming_request`](fn.handle_streaming_request.html).
+///
+/// ```rust
+/// extern crate cgi;
+///
+/// cgi::cgi_streaming_request_main! { |mut request: cgi::StreamingRequest| -> cgi::Response {
+///     let size = std::io::copy(request.body_mut(), &mut std::io::sink()).unwrap();
+///     cgi::text_response(200, format!("Read {} bytes", size))
+/// } }
+/// ```
+macro_rules! cgi_streaming_request_main {
+    ( $func:expr ) => {
+        fn main() {
+            cgi::handle_streaming_request($func);
+        }
+    };
+}
+
+#[macro_export]
+/// Create a `main` function for a Non-Parsed Header (NPH) CGI script
+///
+/// Same as [`cgi_main!`](macro.cgi_main.html), but writes a complete HTTP response, see
+/// [`handle_nph`](fn.handle_nph.html).
+///
+/// ```rust
+/// extern crate cgi;
+///
+/// cgi::cgi_nph_main! { |request: cgi::Request| -> cgi::Response {
+///     cgi::text_response(200, "Hello World")
+/// } }
+/// ```
+macro_rules! cgi_nph_main {
+    ( $func:expr ) => {
+        fn main() {
+            cgi::handle_nph($func);
+        }
+    };
+}
+
+#[macro_export]
+/// Create a `main` function for a CGI script with an `async` function
+///
+/// Same as [`cgi_main!`](macro.cgi_main.html), but the function returns a `Future`, see
+/// [`handle_async`](fn.handle_async.html).
+///
+/// ```rust
+/// extern crate cgi;
+///
+/// cgi::cgi_async_main! { |request: cgi::Request| async move {
+///     cgi::text_response(200, "Hello World")
+/// } }
+/// ```
+macro_rules! cgi_async_main {
+    ( $func:expr ) => {
+        fn main() {
+            cgi::handle_async($func);
+        }
+    };
+}
+
 /// Convert a Result<Response, E> to a Response, by converting an E to a 500.
 pub fn err_to_500<E>(res: Result<Response, E>) -> Response {
     res.unwrap_or(empty_response(500))
@@ -207,20 +763,40 @@ pub fn empty_404() -> Response {
     empty_response(404)
 }
 
+/// The `Location` header for a redirect to `url`
+fn location(url: &str) -> Result<http::header::HeaderValue, CgiError> {
+    http::header::HeaderValue::from_str(url)
+        .map_err(|_| CgiError::InvalidResponseHeader(format!("Invalid redirect URL: {:?}", url)))
+}
+
 /// Return a HTTP Redirect (with this redirect code) to this URL.
+///
+/// If `new_url` can't be a header value (e.g. it has control characters), the error is written to
+/// stderr and an empty `500 Internal Server Error` is returned instead. Use
+/// [`try_redirect`](fn.try_redirect.html) to handle that yourself.
 pub fn redirect<T>(status_code: T, new_url: impl AsRef<str>) -> Response
 where
     http::StatusCode: TryFrom<T>,
     <http::StatusCode as TryFrom<T>>::Error: Into<http::Error>,
 {
-    let new_url: &str = new_url.as_ref();
-    let mut response = empty_response(status_code);
-    response.headers_mut().insert(
-        http::header::LOCATION,
-        http::header::HeaderValue::from_str(new_url).unwrap(),
-    );
+    try_redirect(status_code, new_url).unwrap_or_else(|err| error_response(&err))
+}
 
+/// Return a HTTP Redirect (with this redirect code) to this URL.
+///
+/// Fails if `new_url` can't be a header value, e.g. it has control characters.
+pub fn try_redirect<T>(status_code: T, new_url: impl AsRef<str>) -> Result<Response, CgiError>
+where
+    http::StatusCode: TryFrom<T>,
+    <http::StatusCode as TryFrom<T>>::Error: Into<http::Error>,
+{
+    let location = location(new_url.as_ref())?;
+    let mut response = empty_response(status_code);
     response
+        .headers_mut()
+        .insert(http::header::LOCATION, location);
+
+    Ok(response)
 }
 
 /// Return a HTTP 301 (Permanent) redirect to this URL.
@@ -242,6 +818,108 @@ pub fn redirect_temporary(new_url: impl AsRef<str>) -> Response {
     redirect_302(new_url)
 }
 
+/// Return a HTTP Redirect (with this redirect code) to this path within the programme, resolved
+/// to an absolute URL with [`RequestExt::url_for`](trait.RequestExt.html#tymethod.url_for).
+///
+/// Fails if the URL can't be a header value, like [`try_redirect`](fn.try_redirect.html).
+///
+/// ```rust
+/// extern crate cgi;
+///
+/// cgi::cgi_main! { |request: cgi::Request| -> cgi::Response {
+///     // e.g. to https://example.com/cgi-bin/app/items/3 when the programme is /cgi-bin/app
+///     cgi::err_to_500(cgi::redirect_for(&request, 303, "/items/3"))
+/// } }
+/// ```
+pub fn redirect_for<T, B>(
+    request: &http::Request<B>,
+    status_code: T,
+    path: &str,
+) -> Result<Response, CgiError>
+where
+    http::StatusCode: TryFrom<T>,
+    <http::StatusCode as TryFrom<T>>::Error: Into<http::Error>,
+{
+    try_redirect(status_code, request.url_for(path))
+}
+
+/// Marks a response as a local redirect, see [`local_redirect`](fn.local_redirect.html)
+#[derive(Debug, Clone, Copy)]
+struct LocalRedirect;
+
+fn is_local_redirect_parts(parts: &http::response::Parts) -> bool {
+    parts.extensions.get::<LocalRedirect>().is_some()
+}
+
+/// Whether this response is a local redirect, made by [`local_redirect`](fn.local_redirect.html)
+/// or parsed by [`parse_response`](fn.parse_response.html).
+///
+/// The `Location` header has the path to serve instead.
+pub fn is_local_redirect<B>(response: &http::Response<B>) -> bool {
+    response.extensions().get::<LocalRedirect>().is_some()
+}
+
+/// Return a CGI local redirect (RFC 3875 § 6.2.2) to this path on the same server.
+///
+/// The webserver serves `path` instead, as if it was requested, without the client knowing. Only
+/// a `Location` header is sent; any other headers or body added to the response are ignored.
+///
+/// `path` must be an absolute path (optionally with a query string), e.g. `/other/page?x=1`.
+///
+/// ```rust
+/// extern crate cgi;
+///
+/// cgi::cgi_main! { |request: cgi::Request| -> cgi::Response {
+///     cgi::local_redirect("/static/index.html").unwrap()
+/// } }
+/// ```
+pub fn local_redirect(path: impl AsRef<str>) -> Result<Response, CgiError> {
+    let path: &str = path.as_ref();
+    let invalid = || {
+        CgiError::InvalidResponseHeader(format!(
+            "Local redirect must be to an absolute path: {:?}",
+            path
+        ))
+    };
+    // `//host/path` would be a network path, not a local one
+    if !path.starts_with('/') || path.starts_with("//") {
+        return Err(invalid());
+    }
+    let path_and_query: http::uri::PathAndQuery = path.parse().map_err(|_| invalid())?;
+
+    let mut response = empty_response(200);
+    response.headers_mut().insert(
+        http::header::LOCATION,
+        http::header::HeaderValue::from_str(path_and_query.as_str()).map_err(|_| invalid())?,
+    );
+    response.extensions_mut().insert(LocalRedirect);
+
+    Ok(response)
+}
+
+/// Return a HTTP Redirect (with this redirect code) to this URL, with an HTML document for
+/// clients which don't follow it (RFC 3875 § 6.2.4).
+///
+/// Fails if `new_url` can't be a header value, e.g. it has control characters.
+pub fn redirect_with_document<T, S>(
+    status_code: T,
+    new_url: impl AsRef<str>,
+    body: S,
+) -> Result<Response, CgiError>
+where
+    http::StatusCode: TryFrom<T>,
+    <http::StatusCode as TryFrom<T>>::Error: Into<http::Error>,
+    S: Into<String>,
+{
+    let location = location(new_url.as_ref())?;
+    let mut response = html_response(status_code, body);
+    response
+        .headers_mut()
+        .insert(http::header::LOCATION, location);
+
+    Ok(response)
+}
+
 /// Converts `text` to bytes (UTF8) and sends that as the body with that `status_code` and HTML
 /// `Content-Type` header (`text/html`)
 pub fn html_response<T, S>(status_code: T, body: S) -> Response
@@ -350,38 +1028,109 @@ where
     response.body(body).unwrap()
 }
 
-/// Path of the current programme.
-fn exe_url() -> String {
-    // maybe use http::uri::Uri instead.
-    // note: the http crate that this outputs to only accepts http/https URLs,
-    // so we're going to leave off the scheme entirely
-    match std::env::current_exe() {
-        Ok(p) => p.to_string_lossy().into_owned(),
-        Err(_) => String::new(),
+/// Sends whatever is read from `body` with that status code, and optional content type, `None` for
+/// no `Content-Type` header to be set. For use with [`handle_streaming`](fn.handle_streaming.html).
+///
+/// No `Content-Length` header is set, since the length isn't known in advance.
+///
+/// ```rust,ignore
+/// let file = std::fs::File::open("big.tar").unwrap();
+/// cgi::streaming_response(200, "application/x-tar", file);
+/// ```
+pub fn streaming_response<'a, T, B>(
+    status_code: T,
+    content_type: impl Into<Option<&'a str>>,
+    body: B,
+) -> http::Response<B>
+where
+    http::StatusCode: TryFrom<T>,
+    <http::StatusCode as TryFrom<T>>::Error: Into<http::Error>,
+    B: Read,
+{
+    let content_type: Option<&str> = content_type.into();
+
+    let mut response = http::response::Builder::new().status(status_code);
+
+    if let Some(ct) = content_type {
+        response = response.header(http::header::CONTENT_TYPE, ct);
     }
+
+    response.body(body).unwrap()
 }
 
-fn parse_request(env_vars: HashMap<String, String>, stdin: Vec<u8>) -> Request {
-    let mut req = http::Request::builder();
+/// The URI of the request, from the meta-variables.
+///
+/// The path is `REQUEST_URI` if the server sets it (it's the path exactly as the client sent
+/// it), otherwise it's rebuilt from `SCRIPT_NAME`, `PATH_INFO` & `QUERY_STRING` as RFC 3875
+/// describes. If the host is known (from `HTTP_HOST` or `SERVER_NAME`), the URI is absolute.
+fn request_uri(
+    env_vars: &HashMap<String, String>,
+    meta: &CgiMeta,
+) -> Result<http::Uri, http::Error> {
+    let var = |name: &str| {
+        env_vars
+            .get(name)
+            .map(String::as_str)
+            .filter(|v| !v.is_empty())
+    };
 
-    req = req.method(env_vars.get("REQUEST_METHOD").map_or("GET", String::as_str));
+    let path_and_query = match var("REQUEST_URI") {
+        Some(request_uri) => request_uri.parse::<http::Uri>()?.path_and_query().cloned(),
+        None => {
+            let mut uri = form::percent_encode_path(var("SCRIPT_NAME").unwrap_or_default());
+            uri.push_str(&form::percent_encode_path(
+                var("PATH_INFO").unwrap_or_default(),
+            ));
+            if !uri.starts_with('/') {
+                uri.insert(0, '/');
+            }
+            if let Some(query_string) = var("QUERY_STRING") {
+                uri.push('?');
+                uri.push_str(query_string);
+            }
+            Some(uri.parse()?)
+        }
+    }
+    .unwrap_or_else(|| http::uri::PathAndQuery::from_static("/"));
 
-    let uri = if let Some(request_uri) = env_vars.get("REQUEST_URI") {
-        request_uri.to_owned()
+    let scheme = if meta.https {
+        http::uri::Scheme::HTTPS
     } else {
-        let mut uri = env_vars
-            .get("SCRIPT_URI")
-            .or(env_vars.get("PATH_INFO"))
-            .filter(|val| !val.is_empty())
-            .map_or_else(exe_url, String::clone);
-        if let Some(query_string) = env_vars.get("QUERY_STRING").filter(|val| !val.is_empty()) {
-            uri.push('?');
-            uri.push_str(query_string);
-        }
-        uri
+        http::uri::Scheme::HTTP
+    };
+    let default_port = if meta.https { 443 } else { 80 };
+    let authority = var("HTTP_HOST")
+        .and_then(|host| host.parse::<http::uri::Authority>().ok())
+        .or_else(|| {
+            let server_name = meta.server_name.as_deref()?;
+            match meta.server_port {
+                Some(port) if port != default_port => format!("{}:{}", server_name, port).parse(),
+                _ => server_name.parse(),
+            }
+            .ok()
+        });
+
+    let parts = http::uri::Builder::new().path_and_query(path_and_query.clone());
+    let parts = match authority {
+        Some(authority) => parts.scheme(scheme).authority(authority),
+        None => parts,
     };
+    Ok(parts
+        .build()
+        .unwrap_or_else(|_| http::Uri::from(path_and_query)))
+}
 
-    req = req.uri(uri.as_str());
+fn parse_request<B>(
+    env_vars: HashMap<String, String>,
+    body: B,
+) -> Result<http::Request<B>, CgiError> {
+    let mut req = http::Request::builder();
+
+    req = req.method(env_vars.get("REQUEST_METHOD").map_or("GET", String::as_str));
+
+    let meta = CgiMeta::from_env(&env_vars);
+    let uri = request_uri(&env_vars, &meta)?;
+    req = req.uri(uri);
 
     if let Some(v) = env_vars.get("SERVER_PROTOCOL") {
         if v == "HTTP/0.9" {
@@ -390,10 +1139,15 @@ fn parse_request(env_vars: HashMap<String, String>, stdin: Vec<u8>) -> Request {
             req = req.version(http::version::Version::HTTP_10);
         } else if v == "HTTP/1.1" {
             req = req.version(http::version::Version::HTTP_11);
-        } else if v == "HTTP/2.0" {
+        } else if v == "HTTP/2.0" || v == "HTTP/2" {
             req = req.version(http::version::Version::HTTP_2);
+        } else if v == "HTTP/3.0" || v == "HTTP/3" {
+            req = req.version(http::version::Version::HTTP_3);
+        } else if v == "INCLUDED" {
+            // RFC 3875 § 4.1.16: the request came from a server side include, the version of
+            // the original request isn't known
         } else {
-            unimplemented!("Unsupport HTTP SERVER_PROTOCOL {:?}", v);
+            return Err(CgiError::UnsupportedProtocol(v.to_owned()));
         }
     }
 
@@ -403,9 +1157,16 @@ fn parse_request(env_vars: HashMap<String, String>, stdin: Vec<u8>) -> Request {
             .skip(5)
             .map(|c| if c == '_' { '-' } else { c })
             .collect();
+        // The X-CGI- headers are reserved for the meta-variables we add below. Drop any sent by
+        // the client, so they can't be mistaken for (or sit alongside) the real values.
+        if header.to_ascii_uppercase().starts_with("X-CGI-") {
+            continue;
+        }
         req = req.header(header.as_str(), env_vars[key].as_str().trim());
     }
 
+    req = req.extension(meta);
+
     req = add_header(req, &env_vars, "AUTH_TYPE", "X-CGI-Auth-Type");
     req = add_header(req, &env_vars, "CONTENT_LENGTH", "X-CGI-Content-Length");
     req = add_header(req, &env_vars, "CONTENT_TYPE", "X-CGI-Content-Type");
@@ -432,10 +1193,11 @@ fn parse_request(env_vars: HashMap<String, String>, stdin: Vec<u8>) -> Request {
     req = add_header(req, &env_vars, "SERVER_PROTOCOL", "X-CGI-Server-Protocol");
     req = add_header(req, &env_vars, "SERVER_SOFTWARE", "X-CGI-Server-Software");
 
-    req.body(stdin).unwrap()
+    Ok(req.body(body)?)
 }
 
 // add the CGI request meta-variables as X-CGI- headers
+// (kept for compatibility, `CgiMeta` is the typed way to get these)
 fn add_header(
     req: http::request::Builder,
     env_vars: &HashMap<String, String>,
@@ -449,38 +1211,294 @@ fn add_header(
     }
 }
 
+/// Format a time as an HTTP-date (RFC 9110 § 5.6.7), e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+fn http_date(time: std::time::SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+
+    // civil_from_days from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        // 1970-01-01 was a Thursday
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Write each header as a `name: value` line.
+///
+/// Headers are sorted by name, and a header with several values (e.g. `Set-Cookie`) gets a line
+/// for each value, in the order they were added. Values are written as the raw bytes, so values
+/// which aren't valid UTF-8 (obs-text) are passed through as they are. Values containing CR or LF
+/// would break the response (or inject headers) so are rejected.
+fn write_headers(
+    output: &mut Vec<u8>,
+    headers: &http::HeaderMap,
+    line_ending: &[u8],
+) -> Result<(), CgiError> {
+    let mut keys: Vec<&http::header::HeaderName> = headers.keys().collect();
+    keys.sort_by_key(|h| h.as_str());
+    for key in keys {
+        for value in headers.get_all(key) {
+            let value = value.as_bytes();
+            check_header_value(key.as_str(), value)?;
+            output.extend_from_slice(key.as_str().as_bytes());
+            output.extend_from_slice(b": ");
+            output.extend_from_slice(value);
+            output.extend_from_slice(line_ending);
+        }
+    }
+    Ok(())
+}
+
+/// `HeaderValue` won't normally contain CR or LF, but can if it was created with
+/// `from_maybe_shared_unchecked`, so check before writing it out.
+fn check_header_value(name: &str, value: &[u8]) -> Result<(), CgiError> {
+    if value.iter().any(|b| *b == b'\r' || *b == b'\n') {
+        return Err(CgiError::InvalidResponseHeader(format!(
+            "{} contains CR or LF",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Convert the status line & headers into the appropriate stdout format
+fn serialize_head(parts: &http::response::Parts) -> Result<Vec<u8>, CgiError> {
+    let mut output = Vec::new();
+
+    if is_local_redirect_parts(parts) {
+        // A local redirect is only the Location, without a Status or any other headers
+        let location = &parts.headers[http::header::LOCATION];
+        check_header_value("location", location.as_bytes())?;
+        output.extend_from_slice(b"Location: ");
+        output.extend_from_slice(location.as_bytes());
+        output.extend_from_slice(b"\n\n");
+        return Ok(output);
+    }
+
+    output.extend_from_slice(b"Status: ");
+    output.extend_from_slice(parts.status.as_str().as_bytes());
+    if let Some(reason) = parts.status.canonical_reason() {
+        output.push(b' ');
+        output.extend_from_slice(reason.as_bytes());
+    }
+    output.push(b'\n');
+
+    write_headers(&mut output, &parts.headers, b"\n")?;
+
+    output.push(b'\n');
+
+    Ok(output)
+}
+
 /// Convert the Request into the appropriate stdout format
+fn try_serialize_response(response: Response) -> Result<Vec<u8>, CgiError> {
+    let (parts, mut body) = response.into_parts();
+
+    let mut output = serialize_head(&parts)?;
+
+    if !is_local_redirect_parts(&parts) {
+        output.append(&mut body);
+    }
+
+    Ok(output)
+}
+
+/// Convert the Request into the appropriate stdout format, or if that's not possible, log why &
+/// send an empty 500 response instead.
 fn serialize_response(response: Response) -> Vec<u8> {
-    let mut output = String::new();
-    output.push_str("Status: ");
-    output.push_str(response.status().as_str());
-    if let Some(reason) = response.status().canonical_reason() {
-        output.push(' ');
-        output.push_str(reason);
+    try_serialize_response(response).unwrap_or_else(|err| {
+        try_serialize_response(error_response(&err))
+            .expect("an empty response can always be serialized")
+    })
+}
+
+/// Parse the output of a CGI programme (RFC 3875 § 6) into a `Response`.
+///
+/// This is the opposite of what [`handle`](fn.handle.html) writes to stdout: header lines (ending
+/// in LF or CRLF), a blank line, then the body. The `Status` header becomes the response's status.
+/// Without one, the status is `302 Found` if there's a `Location` header, and `200 OK` otherwise.
+///
+/// A `Location` which is a local path (e.g. `/other/page`), without a `Status`, is a local
+/// redirect, which the webserver should follow itself, see
+/// [`is_local_redirect`](fn.is_local_redirect.html).
+///
+/// ```rust
+/// extern crate cgi;
+///
+/// let response = cgi::parse_response(b"Status: 404 Not Found\r\nContent-Type: text/plain\r\n\r\nNope").unwrap();
+/// assert_eq!(response.status(), 404);
+/// assert_eq!(response.body(), b"Nope");
+/// ```
+pub fn parse_response(output: &[u8]) -> Result<Response, CgiError> {
+    let invalid = |msg: &str| CgiError::InvalidResponse(msg.to_owned());
+
+    let mut lines: Vec<Vec<u8>> = Vec::new();
+    let mut rest = output;
+    loop {
+        let end = rest
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| invalid("No blank line after the headers"))?;
+        let line = &rest[..end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        rest = &rest[end + 1..];
+        match (line, lines.last_mut()) {
+            ([], _) => break,
+            // An obsolete folded line (RFC 9112 § 5.2) continues the previous header
+            ([b' ' | b'\t', ..], Some(previous)) => {
+                previous.push(b' ');
+                previous.extend_from_slice(trim(line));
+            }
+            ([b' ' | b'\t', ..], None) => return Err(invalid("Output starts with whitespace")),
+            _ => lines.push(line.to_vec()),
+        }
     }
-    output.push('\n');
 
-    {
-        let headers = response.headers();
-        let mut keys: Vec<&http::header::HeaderName> = headers.keys().collect();
-        keys.sort_by_key(|h| h.as_str());
-        for key in keys {
-            output.push_str(key.as_str());
-            output.push_str(": ");
-            output.push_str(headers.get(key).unwrap().to_str().unwrap());
-            output.push('\n');
+    let mut response = empty_response(200);
+    let mut status = None;
+    for line in &lines {
+        let colon = line
+            .iter()
+            .position(|b| *b == b':')
+            .ok_or_else(|| invalid("Header line without a ':'"))?;
+        let name = http::header::HeaderName::from_bytes(&line[..colon])
+            .map_err(|_| invalid("Invalid header name"))?;
+        let value = trim(&line[colon + 1..]);
+        if name.as_str() == "status" {
+            let code = value.split(|b| *b == b' ').next().unwrap_or_default();
+            status = Some(
+                http::StatusCode::from_bytes(code).map_err(|_| invalid("Invalid Status header"))?,
+            );
+        } else {
+            let value = http::HeaderValue::from_bytes(value)
+                .map_err(|_| invalid("Invalid header value"))?;
+            response.headers_mut().append(name, value);
+        }
+    }
+
+    match (status, response.headers().get(http::header::LOCATION)) {
+        (Some(status), _) => *response.status_mut() = status,
+        (None, Some(location))
+            if location.as_bytes().starts_with(b"/") && !location.as_bytes().starts_with(b"//") =>
+        {
+            response.extensions_mut().insert(LocalRedirect);
         }
+        (None, Some(_)) => *response.status_mut() = http::StatusCode::FOUND,
+        (None, None) => {}
     }
+    *response.body_mut() = rest.to_vec();
 
-    output.push('\n');
+    Ok(response)
+}
+
+/// Remove leading & trailing spaces and tabs
+fn trim(mut value: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', tail @ ..] = value {
+        value = tail;
+    }
+    while let [head @ .., b' ' | b'\t'] = value {
+        value = head;
+    }
+    value
+}
 
-    let mut output = output.into_bytes();
+/// Convert the Response into a complete HTTP/1.x response, for NPH scripts.
+///
+/// `Date`, `Server` (from `server`) & `Content-Length` headers are added if missing.
+fn try_serialize_http_response(
+    response: Response,
+    version: http::Version,
+    server: Option<&str>,
+    now: std::time::SystemTime,
+) -> Result<Vec<u8>, CgiError> {
+    let (mut parts, mut body) = response.into_parts();
+
+    if is_local_redirect_parts(&parts) {
+        return Err(CgiError::InvalidResponseHeader(
+            "NPH scripts can't do local redirects".to_owned(),
+        ));
+    }
 
-    let (_, mut body) = response.into_parts();
+    let mut output = Vec::new();
+    output.extend_from_slice(match version {
+        http::Version::HTTP_09 | http::Version::HTTP_10 => b"HTTP/1.0 ",
+        _ => b"HTTP/1.1 ",
+    });
+    output.extend_from_slice(parts.status.as_str().as_bytes());
+    output.push(b' ');
+    output.extend_from_slice(
+        parts
+            .status
+            .canonical_reason()
+            .unwrap_or_default()
+            .as_bytes(),
+    );
+    output.extend_from_slice(b"\r\n");
 
+    let headers = &mut parts.headers;
+    if !headers.contains_key(http::header::DATE) {
+        headers.insert(
+            http::header::DATE,
+            http::HeaderValue::from_str(&http_date(now)).unwrap(),
+        );
+    }
+    if !headers.contains_key(http::header::SERVER) {
+        let server = server
+            .and_then(|s| http::HeaderValue::from_str(s).ok())
+            .unwrap_or_else(|| {
+                http::HeaderValue::from_static(concat!("rust-cgi/", env!("CARGO_PKG_VERSION")))
+            });
+        headers.insert(http::header::SERVER, server);
+    }
+    if !headers.contains_key(http::header::CONTENT_LENGTH) {
+        headers.insert(http::header::CONTENT_LENGTH, body.len().into());
+    }
+
+    write_headers(&mut output, headers, b"\r\n")?;
+
+    output.extend_from_slice(b"\r\n");
     output.append(&mut body);
 
-    output
+    Ok(output)
+}
+
+/// Same as `try_serialize_http_response`, but sends a 500 response if the response can't be
+/// serialized.
+fn serialize_http_response(
+    response: Response,
+    version: http::Version,
+    server: Option<&str>,
+    now: std::time::SystemTime,
+) -> Vec<u8> {
+    try_serialize_http_response(response, version, server, now).unwrap_or_else(|err| {
+        try_serialize_http_response(error_response(&err), version, server, now)
+            .expect("an empty response can always be serialized")
+    })
 }
 
 #[cfg(test)]
@@ -497,8 +1515,8 @@ mod tests {
     #[test]
     fn test_empty() {
         let env_vars = env(vec![]);
-        let stdin = Vec::new();
-        let req = parse_request(env_vars, stdin);
+        let stdin: Vec<u8> = Vec::new();
+        let req = parse_request(env_vars, stdin).unwrap();
         assert_eq!(req.method(), &http::method::Method::GET);
         // We don't want to assert any particular values for
         // anything else in the request, but as long as the above
@@ -515,8 +1533,8 @@ mod tests {
             ("HTTP_USER_AGENT", "MyBrowser/1.0"),
             ("QUERY_STRING", "foo=bar&baz=bop"),
         ]);
-        let stdin = Vec::new();
-        let req = parse_request(env_vars, stdin);
+        let stdin: Vec<u8> = Vec::new();
+        let req = parse_request(env_vars, stdin).unwrap();
         assert_eq!(req.method(), &http::method::Method::GET);
         assert_eq!(req.uri(), "/my/path/script?foo=bar&baz=bop");
         assert_eq!(req.uri().path(), "/my/path/script");
@@ -524,6 +1542,105 @@ mod tests {
         assert_eq!(req.version(), http::version::Version::HTTP_10);
         assert_eq!(req.headers()[http::header::USER_AGENT], "MyBrowser/1.0");
         assert_eq!(req.body(), &vec![] as &Vec<u8>);
+
+        let meta = req.cgi_meta().unwrap();
+        assert_eq!(meta.script_name.as_deref(), Some("/my/path/script"));
+        assert_eq!(meta.query_string.as_deref(), Some("foo=bar&baz=bop"));
+    }
+
+    #[test]
+    fn test_parse_request_uri() {
+        let uri = |vars| parse_request(env(vars), ()).unwrap().uri().to_string();
+
+        // Rebuilt from SCRIPT_NAME, PATH_INFO & QUERY_STRING, with the host from SERVER_NAME
+        assert_eq!(
+            uri(vec![
+                ("SCRIPT_NAME", "/cgi-bin/app"),
+                ("PATH_INFO", "/files/a b%.txt"),
+                ("QUERY_STRING", "x=1"),
+                ("SERVER_NAME", "example.com"),
+                ("SERVER_PORT", "80"),
+            ]),
+            "http://example.com/cgi-bin/app/files/a%20b%25.txt?x=1"
+        );
+        assert_eq!(
+            uri(vec![
+                ("SCRIPT_NAME", "/app"),
+                ("SERVER_NAME", "example.com"),
+                ("SERVER_PORT", "8443"),
+                ("HTTPS", "on"),
+            ]),
+            "https://example.com:8443/app"
+        );
+
+        // The Host header is used in preference to SERVER_NAME, unless it's invalid
+        let vars = |host| {
+            vec![
+                ("REQUEST_URI", "/app/x?y"),
+                ("HTTP_HOST", host),
+                ("SERVER_NAME", "internal"),
+                ("SERVER_PORT", "443"),
+                ("REQUEST_SCHEME", "https"),
+            ]
+        };
+        assert_eq!(
+            uri(vars("example.org:8080")),
+            "https://example.org:8080/app/x?y"
+        );
+        assert_eq!(uri(vars("bad host")), "https://internal/app/x?y");
+
+        // Without a host the URI is just the path, never the programme's file
+        assert_eq!(uri(vec![("PATH_INFO", "/x")]), "/x");
+        assert_eq!(uri(vec![]), "/");
+    }
+
+    #[test]
+    fn test_parse_request_protocols() {
+        let version = |protocol: &str| {
+            parse_request(env(vec![("SERVER_PROTOCOL", protocol)]), ()).map(|req| req.version())
+        };
+        assert_eq!(version("HTTP/2").unwrap(), http::version::Version::HTTP_2);
+        assert_eq!(version("HTTP/3").unwrap(), http::version::Version::HTTP_3);
+        assert_eq!(
+            version("INCLUDED").unwrap(),
+            http::version::Version::HTTP_11
+        );
+
+        let err = version("SPDY/3").unwrap_err();
+        assert!(matches!(err, CgiError::UnsupportedProtocol(ref p) if p == "SPDY/3"));
+        assert_eq!(err.status(), 505);
+    }
+
+    #[test]
+    fn test_parse_request_invalid() {
+        let err = parse_request(env(vec![("REQUEST_URI", "/with space")]), ()).unwrap_err();
+        assert!(matches!(err, CgiError::InvalidRequest(_)));
+        assert_eq!(err.status(), 400);
+
+        let err = parse_request(env(vec![("HTTP_X_FOO", "bad\x01value")]), ()).unwrap_err();
+        assert!(matches!(err, CgiError::InvalidRequest(_)));
+        assert_eq!(
+            serialize_response(error_response(&err)),
+            b"Status: 400 Bad Request\n\n"
+        );
+    }
+
+    #[test]
+    fn test_spoofed_cgi_headers() {
+        let env_vars = env(vec![
+            ("REMOTE_USER", "bob"),
+            ("HTTP_X_CGI_REMOTE_USER", "admin"),
+            ("HTTP_X_CGI_REMOTE_ADDR", "127.0.0.1"),
+            ("HTTP_X_CGI_SOMETHING_ELSE", "hello"),
+            ("HTTP_X_CGIFOO", "not in the namespace"),
+        ]);
+        let req = parse_request(env_vars, ()).unwrap();
+
+        let remote_user: Vec<_> = req.headers().get_all("X-CGI-Remote-User").iter().collect();
+        assert_eq!(remote_user, vec!["bob"]);
+        assert!(!req.headers().contains_key("X-CGI-Remote-Addr"));
+        assert!(!req.headers().contains_key("X-CGI-Something-Else"));
+        assert_eq!(req.headers()["X-CGIFOO"], "not in the namespace");
     }
 
     fn test_serialized_response(resp: http::response::Builder, body: &str, expected_output: &str) {
@@ -560,6 +1677,233 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_serialize_multiple_values() {
+        let mut resp = empty_response(200);
+        let headers = resp.headers_mut();
+        headers.append("Vary", "Accept".parse().unwrap());
+        headers.append("Link", "</style.css>; rel=preload".parse().unwrap());
+        headers.append("Vary", "Cookie".parse().unwrap());
+        headers.append("Link", "</app.js>; rel=preload".parse().unwrap());
+        let expected_headers = resp.headers().clone();
+
+        let output = String::from_utf8(try_serialize_response(resp).unwrap()).unwrap();
+        assert_eq!(output, "Status: 200 OK\nlink: </style.css>; rel=preload\nlink: </app.js>; rel=preload\nvary: Accept\nvary: Cookie\n\n");
+
+        // And back again
+        let mut headers = http::HeaderMap::new();
+        for line in output.lines().skip(1).take_while(|l| !l.is_empty()) {
+            let (name, value) = line.split_once(": ").unwrap();
+            headers.append(
+                http::header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        assert_eq!(headers, expected_headers);
+    }
+
+    #[test]
+    fn test_serialize_opaque_header() {
+        let mut resp = empty_response(200);
+        resp.headers_mut().insert(
+            "X-Latin1",
+            http::HeaderValue::from_bytes(b"caf\xe9").unwrap(),
+        );
+
+        assert_eq!(
+            try_serialize_response(resp).unwrap(),
+            b"Status: 200 OK\nx-latin1: caf\xe9\n\n"
+        );
+    }
+
+    #[test]
+    fn test_header_value_crlf() {
+        assert!(check_header_value("x-ok", b"fine\tvalue").is_ok());
+        let err = check_header_value("x-injected", b"a\r\nSet-Cookie: evil=1").unwrap_err();
+        assert!(matches!(err, CgiError::InvalidResponseHeader(_)));
+        assert!(check_header_value("x-injected", b"a\nb").is_err());
+    }
+
+    #[test]
+    fn test_serialize_http_response() {
+        let now = std::time::UNIX_EPOCH + std::time::Duration::from_secs(784111777);
+
+        let output = serialize_http_response(
+            text_response(200, "Hi"),
+            http::Version::HTTP_11,
+            Some("Apache/2.4"),
+            now,
+        );
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\ncontent-length: 2\r\ncontent-type: text/plain; charset=utf-8\r\ndate: Sun, 06 Nov 1994 08:49:37 GMT\r\nserver: Apache/2.4\r\n\r\nHi"
+        );
+
+        let output = serialize_http_response(empty_404(), http::Version::HTTP_10, None, now);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("HTTP/1.0 404 Not Found\r\ncontent-length: 0\r\ndate: Sun, 06 Nov 1994 08:49:37 GMT\r\nserver: rust-cgi/{}\r\n\r\n", env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    #[test]
+    fn test_handle_nph() {
+        let input = std::io::Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        handle_nph_with_io(
+            |_req: Request| empty_response(204),
+            HashMap::new(),
+            input,
+            &mut output,
+            &Limits::default(),
+        );
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(output.contains("\r\ndate: "));
+        assert!(output.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_local_redirect() {
+        let mut resp = local_redirect("/other/page?x=1").unwrap();
+        resp.headers_mut()
+            .insert("X-Ignored", http::HeaderValue::from_static("yes"));
+        *resp.body_mut() = b"ignored".to_vec();
+
+        assert_eq!(
+            String::from_utf8(serialize_response(resp)).unwrap(),
+            "Location: /other/page?x=1\n\n"
+        );
+
+        assert!(local_redirect("relative/path").is_err());
+        assert!(local_redirect("//evil.example.com/").is_err());
+        assert!(local_redirect("https://example.com/").is_err());
+        assert!(local_redirect("/with space").is_err());
+    }
+
+    #[test]
+    fn test_redirect() {
+        let response = redirect(302, "https://example.com/");
+        assert_eq!(response.status(), 302);
+        assert_eq!(response.headers()["location"], "https://example.com/");
+
+        // e.g. from user input, which could have anything in it
+        let response = redirect_302("https://example.com/\r\nX-Evil: 1");
+        assert_eq!(response.status(), 500);
+        assert!(response.headers().get("location").is_none());
+
+        let err = try_redirect(301, "/a\nb").unwrap_err();
+        assert!(matches!(err, CgiError::InvalidResponseHeader(_)));
+    }
+
+    #[test]
+    fn test_redirect_with_document() {
+        assert_eq!(
+            String::from_utf8(serialize_response(redirect_with_document(
+                302,
+                "https://example.com/",
+                "<a href=\"https://example.com/\">Moved</a>"
+            )
+            .unwrap()))
+            .unwrap(),
+            "Status: 302 Found\ncontent-length: 40\ncontent-type: text/html; charset=utf-8\nlocation: https://example.com/\n\n<a href=\"https://example.com/\">Moved</a>"
+        );
+
+        let err =
+            redirect_with_document(302, "https://example.com/\r\nX-Evil: 1", "Moved").unwrap_err();
+        assert!(matches!(err, CgiError::InvalidResponseHeader(_)));
+        assert_eq!(err.status(), 500);
+    }
+
+    #[test]
+    fn test_redirect_for() {
+        let response = testing::TestRequest::post("/cgi-bin/app/items")
+            .script_name("/cgi-bin/app")
+            .header("Host", "example.com")
+            .run(|request| redirect_for(&request, 303, "/items/3").unwrap());
+        assert_eq!(response.status(), 303);
+        assert_eq!(
+            response.headers()["location"],
+            "http://example.com/cgi-bin/app/items/3"
+        );
+
+        // e.g. from form input, which could have anything in it
+        let response = testing::TestRequest::get("/")
+            .run(|request| redirect_for(&request, 303, "/search/a\r\nb ü").unwrap());
+        assert_eq!(
+            response.headers()["location"],
+            "http://localhost/search/a%0D%0Ab%20%C3%BC"
+        );
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let response = parse_response(
+            b"Status: 404 Not Found\r\nContent-Type: text/plain\r\nX-A: 1\r\nX-A:  2 \r\n\r\nGone",
+        )
+        .unwrap();
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        assert_eq!(
+            response.headers().get_all("x-a").iter().collect::<Vec<_>>(),
+            vec!["1", "2"]
+        );
+        assert_eq!(response.body(), b"Gone");
+
+        let response = parse_response(b"Location: https://example.com/\n\n").unwrap();
+        assert_eq!(response.status(), 302);
+
+        let response = parse_response(b"Content-Type: text/plain\n\n").unwrap();
+        assert_eq!(response.status(), 200);
+
+        let response = parse_response(b"Location: /other?x=1\n\n").unwrap();
+        assert!(is_local_redirect(&response));
+        assert_eq!(response.headers()["location"], "/other?x=1");
+        let response = parse_response(b"Location: //example.com/\n\n").unwrap();
+        assert!(!is_local_redirect(&response));
+        let response = parse_response(b"Status: 303 See Other\nLocation: /other\n\n").unwrap();
+        assert!(!is_local_redirect(&response));
+        assert_eq!(response.status(), 303);
+
+        let response = parse_response(b"X-Folded: one\n  two\n\tthree\n\n").unwrap();
+        assert_eq!(response.headers()["x-folded"], "one two three");
+
+        assert!(parse_response(b"").is_err());
+        assert!(parse_response(b" X: folded\n\n").is_err());
+        assert!(parse_response(b"Content-Type: text/plain\n").is_err());
+        assert!(parse_response(b"Not a header\n\n").is_err());
+        assert!(parse_response(b"Status: abc\n\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_response_round_trip() {
+        let mut response = html_response(201, "<p>Hi</p>");
+        response
+            .headers_mut()
+            .append("X-Multi", http::HeaderValue::from_static("1"));
+        response
+            .headers_mut()
+            .append("X-Multi", http::HeaderValue::from_static("2"));
+        let parsed = parse_response(&serialize_response(response.clone())).unwrap();
+        assert_eq!(parsed.status(), response.status());
+        assert_eq!(parsed.headers(), response.headers());
+        assert_eq!(parsed.body(), response.body());
+
+        let parsed = parse_response(&serialize_response(local_redirect("/a").unwrap())).unwrap();
+        assert!(is_local_redirect(&parsed));
+    }
+
+    #[test]
+    fn test_http_date() {
+        let at = |secs| std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        assert_eq!(http_date(at(0)), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(http_date(at(784111777)), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(at(951782400)), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(http_date(at(4102444799)), "Thu, 31 Dec 2099 23:59:59 GMT");
+    }
+
     #[test]
     fn test_shortcuts1() {
         assert_eq!(std::str::from_utf8(&serialize_response(html_response(200, "<html><body><h1>Hello World</h1></body></html>"))).unwrap(),
@@ -609,9 +1953,11 @@ mod tests {
 
         try_handle_with_io(
             |_req: Request| Ok::<http::Response<Vec<u8>>, String>(text_response(200, "All good")),
+            HashMap::new(),
             input,
             &mut output,
             &mut error,
+            &Limits::default(),
         );
 
         let written = output.into_inner().unwrap();
@@ -619,6 +1965,62 @@ mod tests {
         assert_eq!(error.into_inner().unwrap().len(), 0);
     }
 
+    #[test]
+    fn test_handle_streaming() {
+        let input = std::io::Cursor::new(vec![]);
+        let mut output = std::io::BufWriter::new(Vec::new());
+
+        handle_streaming_with_io(
+            |_req: Request| streaming_response(200, "text/plain", "streamed body".as_bytes()),
+            HashMap::new(),
+            input,
+            &mut output,
+            &Limits::default(),
+        );
+
+        let written = output.into_inner().unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "Status: 200 OK\ncontent-type: text/plain\n\nstreamed body"
+        );
+    }
+
+    #[test]
+    fn test_request_body() {
+        let mut body = RequestBody::new(std::io::Cursor::new(b"0123456789".to_vec()), 4);
+        assert_eq!(body.remaining(), 4);
+
+        let mut contents = Vec::new();
+        body.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"0123");
+        assert_eq!(body.remaining(), 0);
+    }
+
+    #[test]
+    fn test_handle_streaming_request() {
+        let input = std::io::Cursor::new(b"some body".to_vec());
+        let mut output = std::io::BufWriter::new(Vec::new());
+
+        handle_streaming_request_with_io(
+            |mut req: http::Request<RequestBody<_>>| {
+                let mut body = String::new();
+                req.body_mut().read_to_string(&mut body).unwrap();
+                text_response(200, body)
+            },
+            HashMap::new(),
+            input,
+            &mut output,
+            &Limits::default(),
+        );
+
+        // No CONTENT_LENGTH, so nothing is read from stdin
+        let written = output.into_inner().unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "Status: 200 OK\ncontent-length: 0\ncontent-type: text/plain; charset=utf-8\n\n"
+        );
+    }
+
     #[test]
     fn test_handle_error() {
         let input = std::io::Cursor::new(vec![]);
@@ -627,9 +2029,11 @@ mod tests {
 
         try_handle_with_io(
             |_req: Request| Err("Not good"),
+            HashMap::new(),
             input,
             &mut output,
             &mut error,
+            &Limits::default(),
         );
 
         let written = output.into_inner().unwrap();
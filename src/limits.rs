@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::time::Duration;
+
+use crate::{
+    content_length, debug, env_vars, handle_nph_with_io, handle_streaming_request_with_io,
+    handle_streaming_with_io, handle_with_io, try_handle_with_io, CgiError, Request, Response,
+    StreamingRequest,
+};
+
+/// Read a request body of `expected` bytes.
+///
+/// This reads as the data arrives, rather than allocating `expected` bytes up front, and never
+/// more than that, as a general read_to_end() can block if the webserver doesn't close stdin.
+pub(crate) fn read_exactly<R: Read>(stdin: R, expected: u64) -> Result<Vec<u8>, CgiError> {
+    let mut body = Vec::new();
+    stdin.take(expected).read_to_end(&mut body)?;
+    if (body.len() as u64) < expected {
+        return Err(CgiError::IncompleteBody {
+            expected,
+            received: body.len() as u64,
+        });
+    }
+    Ok(body)
+}
+
+/// Limits on reading the request body, for a CGI programme, or a
+/// [FastCGI](fastcgi/fn.serve.html) or [SCGI](scgi/fn.serve.html) server.
+///
+/// By default the whole `CONTENT_LENGTH` is read, however long it is, and however long it takes.
+/// Set a maximum size, and the client gets a `413 Payload Too Large` for larger bodies, without
+/// them being read. Set a read timeout, and if the body hasn't all arrived by then the client
+/// gets a `408 Request Timeout`. Either way, a body which is shorter than `CONTENT_LENGTH` gets a
+/// `400 Bad Request`. In all these cases, your function isn't called.
+///
+/// ```rust,no_run
+/// extern crate cgi;
+///
+/// fn main() {
+///     cgi::Limits::new()
+///         .max_body_size(1024 * 1024)
+///         .read_timeout(std::time::Duration::from_secs(30))
+///         .handle(|request: cgi::Request| {
+///             cgi::text_response(200, format!("{} bytes", request.body().len()))
+///         })
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    max_body_size: Option<u64>,
+    read_timeout: Option<Duration>,
+}
+
+impl Limits {
+    /// No limits
+    pub fn new() -> Limits {
+        Limits::default()
+    }
+
+    /// The largest request body (in bytes) which will be accepted
+    pub fn max_body_size(mut self, bytes: u64) -> Limits {
+        self.max_body_size = Some(bytes);
+        self
+    }
+
+    /// How long to wait for the whole request body to be read.
+    ///
+    /// This doesn't apply to [`handle_streaming_request`](#method.handle_streaming_request), as
+    /// the function reads the body itself, nor to the FastCGI & SCGI servers, where the webserver
+    /// sends the body.
+    pub fn read_timeout(mut self, timeout: Duration) -> Limits {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Fails if `CONTENT_LENGTH` is more than the maximum body size
+    pub(crate) fn check_length(&self, env_vars: &HashMap<String, String>) -> Result<u64, CgiError> {
+        self.check(content_length(env_vars) as u64)
+    }
+
+    /// Fails if `length` is more than the maximum body size
+    pub(crate) fn check(&self, length: u64) -> Result<u64, CgiError> {
+        match self.max_body_size {
+            Some(limit) if length > limit => Err(CgiError::BodyTooLarge { length, limit }),
+            _ => Ok(length),
+        }
+    }
+
+    /// Read the request body from `stdin`, within these limits
+    pub(crate) fn read_body<R>(
+        &self,
+        env_vars: &HashMap<String, String>,
+        stdin: R,
+    ) -> Result<Vec<u8>, CgiError>
+    where
+        R: Read + Send + 'static,
+    {
+        let expected = self.check_length(env_vars)?;
+        let read = move || read_exactly(stdin, expected);
+
+        match self.read_timeout {
+            None => read(),
+            Some(timeout) => {
+                // Reading can't be interrupted, so it's done on another thread, which is left
+                // blocked if it times out
+                let (sender, receiver) = std::sync::mpsc::channel();
+                std::thread::spawn(move || sender.send(read()));
+                match receiver.recv_timeout(timeout) {
+                    Ok(body) => body,
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                        Err(CgiError::Timeout(timeout))
+                    }
+                    Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                        Err(CgiError::Io(std::io::Error::new(
+                            std::io::ErrorKind::Other,
+                            "Reading the request body failed",
+                        )))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Same as [`cgi::handle`](fn.handle.html), within these limits
+    pub fn handle<F>(self, func: F)
+    where
+        F: FnOnce(Request) -> Response,
+    {
+        match debug::request() {
+            Some(request) => debug::run(request, |env_vars, stdin, stdout| {
+                handle_with_io(func, env_vars, stdin, stdout, &self)
+            }),
+            None => handle_with_io(func, env_vars(), std::io::stdin(), std::io::stdout(), &self),
+        }
+    }
+
+    /// Same as [`cgi::try_handle`](fn.try_handle.html), within these limits
+    pub fn try_handle<E, F>(self, func: F)
+    where
+        E: std::fmt::Debug,
+        F: FnOnce(Request) -> Result<Response, E>,
+    {
+        match debug::request() {
+            Some(request) => debug::run(request, |env_vars, stdin, stdout| {
+                try_handle_with_io(func, env_vars, stdin, stdout, std::io::stderr(), &self)
+            }),
+            None => try_handle_with_io(
+                func,
+                env_vars(),
+                std::io::stdin(),
+                std::io::stdout(),
+                std::io::stderr(),
+                &self,
+            ),
+        }
+    }
+
+    /// Same as [`cgi::handle_streaming`](fn.handle_streaming.html), within these limits
+    pub fn handle_streaming<F, B>(self, func: F)
+    where
+        F: FnOnce(Request) -> http::Response<B>,
+        B: Read,
+    {
+        match debug::request() {
+            Some(request) => debug::run(request, |env_vars, stdin, stdout| {
+                handle_streaming_with_io(func, env_vars, stdin, stdout, &self)
+            }),
+            None => handle_streaming_with_io(
+                func,
+                env_vars(),
+                std::io::stdin(),
+                std::io::stdout(),
+                &self,
+            ),
+        }
+    }
+
+    /// Same as [`cgi::handle_streaming_request`](fn.handle_streaming_request.html), within the
+    /// maximum body size
+    pub fn handle_streaming_request<F>(self, func: F)
+    where
+        F: FnOnce(StreamingRequest) -> Response,
+    {
+        match debug::request() {
+            Some(request) => {
+                debug::no_body(&request);
+                debug::run(request, |env_vars, _, stdout| {
+                    handle_streaming_request_with_io(
+                        func,
+                        env_vars,
+                        std::io::stdin(),
+                        stdout,
+                        &self,
+                    )
+                })
+            }
+            None => handle_streaming_request_with_io(
+                func,
+                env_vars(),
+                std::io::stdin(),
+                std::io::stdout(),
+                &self,
+            ),
+        }
+    }
+
+    /// Same as [`cgi::handle_nph`](fn.handle_nph.html), within these limits
+    pub fn handle_nph<F>(self, func: F)
+    where
+        F: FnOnce(Request) -> Response,
+    {
+        match debug::request() {
+            Some(request) => debug::run(request, |env_vars, stdin, stdout| {
+                handle_nph_with_io(func, env_vars, stdin, stdout, &self)
+            }),
+            None => {
+                handle_nph_with_io(func, env_vars(), std::io::stdin(), std::io::stdout(), &self)
+            }
+        }
+    }
+
+    /// Same as [`cgi::handle_async`](fn.handle_async.html), within these limits
+    pub fn handle_async<F, Fut>(self, func: F)
+    where
+        F: FnOnce(Request) -> Fut,
+        Fut: std::future::Future<Output = Response>,
+    {
+        self.handle(|request| crate::executor::block_on(func(request)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn env(content_length: &str) -> HashMap<String, String> {
+        let mut env_vars = HashMap::new();
+        env_vars.insert("CONTENT_LENGTH".to_owned(), content_length.to_owned());
+        env_vars
+    }
+
+    /// Sends some of the body, then never any more
+    struct Stalled(Option<Vec<u8>>);
+
+    impl Read for Stalled {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.take() {
+                Some(data) => Cursor::new(data).read(buf),
+                None => {
+                    std::thread::sleep(Duration::from_secs(60));
+                    Ok(0)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_body() {
+        let limits = Limits::new().max_body_size(5);
+        let body =
+            |content_length, stdin: &'static [u8]| limits.read_body(&env(content_length), stdin);
+
+        assert_eq!(body("5", b"hello, more").unwrap(), b"hello");
+        assert_eq!(body("", b"hello").unwrap(), b"");
+
+        let err = body("6", b"hello!").unwrap_err();
+        assert!(matches!(
+            err,
+            CgiError::BodyTooLarge {
+                length: 6,
+                limit: 5
+            }
+        ));
+        assert_eq!(err.status(), 413);
+
+        let err = body("5", b"hel").unwrap_err();
+        assert!(matches!(
+            err,
+            CgiError::IncompleteBody {
+                expected: 5,
+                received: 3
+            }
+        ));
+        assert_eq!(err.status(), 400);
+
+        // A huge CONTENT_LENGTH isn't allocated up front
+        let err = Limits::new()
+            .read_body(&env("18446744073709551615"), &b"tiny"[..])
+            .unwrap_err();
+        assert!(matches!(err, CgiError::IncompleteBody { received: 4, .. }));
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let limits = Limits::new().read_timeout(Duration::from_millis(50));
+
+        let stdin = Stalled(Some(b"the body".to_vec()));
+        assert_eq!(limits.read_body(&env("8"), stdin).unwrap(), b"the body");
+
+        let stdin = Stalled(Some(b"the".to_vec()));
+        let err = limits.read_body(&env("8"), stdin).unwrap_err();
+        assert!(matches!(err, CgiError::Timeout(_)));
+        assert_eq!(err.status(), 408);
+    }
+
+    #[test]
+    fn test_handle() {
+        let run = |limits: &Limits, content_length, stdin: &'static [u8]| {
+            let mut output = Vec::new();
+            handle_with_io(
+                |request: Request| {
+                    crate::text_response(200, String::from_utf8(request.into_body()).unwrap())
+                },
+                env(content_length),
+                stdin,
+                &mut output,
+                limits,
+            );
+            String::from_utf8(output).unwrap()
+        };
+
+        let limits = Limits::new().max_body_size(4);
+        assert!(run(&limits, "4", b"body").ends_with("\n\nbody"));
+        assert_eq!(
+            run(&limits, "10", b"too long!!"),
+            "Status: 413 Payload Too Large\n\n"
+        );
+        assert_eq!(run(&limits, "3", b"ab"), "Status: 400 Bad Request\n\n");
+    }
+
+    #[test]
+    fn test_streaming_request() {
+        let run = |limits: &Limits, content_length, stdin: &'static [u8]| {
+            let mut output = Vec::new();
+            handle_streaming_request_with_io(
+                |mut request: http::Request<crate::RequestBody<&[u8]>>| {
+                    let mut body = Vec::new();
+                    match request.body_mut().read_to_end(&mut body) {
+                        Ok(_) => crate::text_response(200, String::from_utf8(body).unwrap()),
+                        Err(err) => crate::text_response(400, err.to_string()),
+                    }
+                },
+                env(content_length),
+                stdin,
+                &mut output,
+                limits,
+            );
+            String::from_utf8(output).unwrap()
+        };
+
+        let limits = Limits::new().max_body_size(4);
+        assert!(run(&limits, "4", b"body").ends_with("\n\nbody"));
+        assert_eq!(
+            run(&limits, "10", b"too long!!"),
+            "Status: 413 Payload Too Large\n\n"
+        );
+        // The function finds out the body is short when it reads it
+        assert!(run(&limits, "3", b"ab").starts_with("Status: 400 Bad Request\n"));
+    }
+}
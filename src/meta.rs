@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::form::{percent_encode_path, percent_encode_query};
+use crate::proxy::{direct_client_info, ClientInfo};
+use crate::router::RouteParams;
+
+/// The CGI request meta-variables ([RFC 3875 § 4.1](https://tools.ietf.org/html/rfc3875#section-4.1))
+/// that the webserver set for this request.
+///
+/// This is added to the extensions of every `Request`, get it with
+/// [`RequestExt::cgi_meta`](trait.RequestExt.html#tymethod.cgi_meta). Unlike the `X-CGI-*`
+/// headers, it can't be spoofed by the client sending headers with the same name.
+///
+/// Meta-variables which are set, but empty, are treated as not set.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct CgiMeta {
+    /// `AUTH_TYPE`, e.g. `Basic`
+    pub auth_type: Option<String>,
+    /// `CONTENT_LENGTH`, the size of the request body
+    pub content_length: Option<u64>,
+    /// `CONTENT_TYPE` of the request body
+    pub content_type: Option<String>,
+    /// `GATEWAY_INTERFACE`, e.g. `CGI/1.1`
+    pub gateway_interface: Option<String>,
+    /// `PATH_INFO`, the part of the path after the script
+    pub path_info: Option<String>,
+    /// `PATH_TRANSLATED`, `PATH_INFO` mapped onto the filesystem
+    pub path_translated: Option<String>,
+    /// `QUERY_STRING`, without the leading `?`
+    pub query_string: Option<String>,
+    /// `REMOTE_ADDR`, the address of the client (or the last proxy)
+    pub remote_addr: Option<IpAddr>,
+    /// `REMOTE_HOST`, the hostname of the client
+    pub remote_host: Option<String>,
+    /// `REMOTE_IDENT`, the identity of the client from RFC 1413
+    pub remote_ident: Option<String>,
+    /// `REMOTE_USER`, the user the client authenticated as
+    pub remote_user: Option<String>,
+    /// `REQUEST_METHOD`, e.g. `GET`
+    pub request_method: Option<String>,
+    /// `SCRIPT_NAME`, the URL path of the script
+    pub script_name: Option<String>,
+    /// `SERVER_NAME`, the hostname the request was sent to
+    pub server_name: Option<String>,
+    /// `SERVER_PORT`, the port the request was sent to
+    pub server_port: Option<u16>,
+    /// `SERVER_PROTOCOL`, e.g. `HTTP/1.1`
+    pub server_protocol: Option<String>,
+    /// `SERVER_SOFTWARE`, e.g. `Apache/2.4.62`
+    pub server_software: Option<String>,
+    /// Whether the request was made over TLS (`HTTPS` is `on`, or `REQUEST_SCHEME` is `https`)
+    pub https: bool,
+    vars: HashMap<String, String>,
+}
+
+impl CgiMeta {
+    pub(crate) fn from_env(env_vars: &HashMap<String, String>) -> Self {
+        let vars: HashMap<String, String> = env_vars
+            .iter()
+            .filter(|(k, v)| !k.starts_with("HTTP_") && !v.is_empty())
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
+        let var = |name: &str| vars.get(name).cloned();
+
+        let https = matches!(vars.get("HTTPS"), Some(v) if v.eq_ignore_ascii_case("on") || v == "1")
+            || matches!(vars.get("REQUEST_SCHEME"), Some(v) if v.eq_ignore_ascii_case("https"));
+
+        CgiMeta {
+            auth_type: var("AUTH_TYPE"),
+            content_length: vars.get("CONTENT_LENGTH").and_then(|v| v.parse().ok()),
+            content_type: var("CONTENT_TYPE"),
+            gateway_interface: var("GATEWAY_INTERFACE"),
+            path_info: var("PATH_INFO"),
+            path_translated: var("PATH_TRANSLATED"),
+            query_string: var("QUERY_STRING"),
+            remote_addr: vars
+                .get("REMOTE_ADDR")
+                .and_then(|v| v.trim_start_matches('[').trim_end_matches(']').parse().ok()),
+            remote_host: var("REMOTE_HOST"),
+            remote_ident: var("REMOTE_IDENT"),
+            remote_user: var("REMOTE_USER"),
+            request_method: var("REQUEST_METHOD"),
+            script_name: var("SCRIPT_NAME"),
+            server_name: var("SERVER_NAME"),
+            server_port: vars.get("SERVER_PORT").and_then(|v| v.parse().ok()),
+            server_protocol: var("SERVER_PROTOCOL"),
+            server_software: var("SERVER_SOFTWARE"),
+            https,
+            vars,
+        }
+    }
+
+    /// Any other meta-variable the webserver set, e.g. `DOCUMENT_ROOT` or `UNIQUE_ID`.
+    ///
+    /// Client headers (`HTTP_*`) are not included, use the request's headers for them.
+    pub fn var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(String::as_str)
+    }
+}
+
+mod private {
+    pub trait Sealed {}
+
+    impl<B> Sealed for http::Request<B> {}
+}
+
+/// Extra methods for requests created by this crate.
+///
+/// This is implemented for `http::Request`, and can't be implemented for other types, so that
+/// methods can be added to it.
+pub trait RequestExt: private::Sealed {
+    /// The CGI meta-variables for this request.
+    ///
+    /// `None` if the request wasn't created by this crate (e.g. you built it yourself).
+    fn cgi_meta(&self) -> Option<&CgiMeta>;
+
+    /// The value of this parameter (e.g. `id` for `/users/:id`) or wildcard (e.g. `path` for
+    /// `/files/*path`) from the [`Router`](struct.Router.html) route which matched this request.
+    fn route_param(&self, name: &str) -> Option<&str>;
+
+    /// The absolute URL of this path within the programme, e.g. `/items/3` becomes
+    /// `https://example.com/cgi-bin/app/items/3` when the programme is at `/cgi-bin/app`.
+    ///
+    /// The path (which can have a query string) is relative to `SCRIPT_NAME`, so links & redirects
+    /// made with this keep working when the programme is moved to another directory or host. The
+    /// scheme & host are the request's (or the client's, if
+    /// [`TrustedProxies::apply`](struct.TrustedProxies.html#method.apply) was used). URLs which
+    /// are already absolute (`https://...` or `//host/...`) are returned unchanged.
+    ///
+    /// The path isn't URL-encoded (e.g. it can be `/users/Jürgen`), it's encoded here. The query
+    /// string is used as it is, with only characters which aren't allowed in a URL escaped.
+    fn url_for(&self, path: &str) -> String;
+
+    /// Who made the request, as found by
+    /// [`TrustedProxies::apply`](struct.TrustedProxies.html#method.apply). If that wasn't used,
+    /// no proxies are trusted, so this is `REMOTE_ADDR` and the request's scheme & host.
+    fn client_info(&self) -> ClientInfo;
+}
+
+impl<B> RequestExt for http::Request<B> {
+    fn cgi_meta(&self) -> Option<&CgiMeta> {
+        self.extensions().get::<CgiMeta>()
+    }
+
+    fn route_param(&self, name: &str) -> Option<&str> {
+        self.extensions()
+            .get::<RouteParams>()
+            .and_then(|params| params.get(name))
+    }
+
+    fn url_for(&self, path: &str) -> String {
+        let has_scheme = matches!(path.parse::<http::Uri>(), Ok(uri) if uri.scheme().is_some());
+        if has_scheme || path.starts_with("//") {
+            return path.to_owned();
+        }
+
+        let mut url = String::new();
+        let client = self.client_info();
+        if let Some(host) = client.host {
+            url.push_str(&format!("{}://{}", client.scheme, host));
+        }
+        let script_name = self
+            .cgi_meta()
+            .and_then(|meta| meta.script_name.as_deref())
+            .unwrap_or_default();
+        url.push_str(percent_encode_path(script_name).trim_end_matches('/'));
+        if !path.starts_with('/') {
+            url.push('/');
+        }
+        let (path, query) = match path.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (path, None),
+        };
+        url.push_str(&percent_encode_path(path));
+        if let Some(query) = query {
+            url.push('?');
+            url.push_str(&percent_encode_query(query));
+        }
+        url
+    }
+
+    fn client_info(&self) -> ClientInfo {
+        match self.extensions().get::<ClientInfo>() {
+            Some(client) => client.clone(),
+            None => direct_client_info(self),
+        }
+    }
+}
+
+/// The `Content-Type` of the request body. For requests not created by this crate, this is
+/// taken from the header instead of `CONTENT_TYPE`.
+pub(crate) fn content_type<B>(request: &http::Request<B>) -> Option<&str> {
+    request
+        .cgi_meta()
+        .and_then(|meta| meta.content_type.as_deref())
+        .or_else(|| {
+            request
+                .headers()
+                .get(http::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(input: Vec<(&str, &str)>) -> HashMap<String, String> {
+        input
+            .into_iter()
+            .map(|(a, b)| (a.to_owned(), b.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_env() {
+        let meta = CgiMeta::from_env(&env(vec![
+            ("REMOTE_ADDR", "2001:db8::1"),
+            ("SERVER_PORT", "8443"),
+            ("SCRIPT_NAME", "/cgi-bin/app"),
+            ("PATH_INFO", ""),
+            ("HTTPS", "on"),
+            ("DOCUMENT_ROOT", "/var/www"),
+            ("HTTP_X_CGI_REMOTE_ADDR", "10.0.0.1"),
+        ]));
+
+        assert_eq!(meta.remote_addr, Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(meta.server_port, Some(8443));
+        assert_eq!(meta.script_name.as_deref(), Some("/cgi-bin/app"));
+        assert_eq!(meta.path_info, None);
+        assert!(meta.https);
+        assert_eq!(meta.var("DOCUMENT_ROOT"), Some("/var/www"));
+        assert_eq!(meta.var("HTTP_X_CGI_REMOTE_ADDR"), None);
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let meta = CgiMeta::from_env(&env(vec![
+            ("REMOTE_ADDR", "not an ip"),
+            ("SERVER_PORT", "99999"),
+            ("HTTPS", "off"),
+        ]));
+
+        assert_eq!(meta.remote_addr, None);
+        assert_eq!(meta.server_port, None);
+        assert!(!meta.https);
+    }
+
+    #[test]
+    fn test_url_for() {
+        let url_for = |request: crate::testing::TestRequest, path| {
+            request
+                .run(move |request| crate::text_response(200, request.url_for(path)))
+                .text()
+                .into_owned()
+        };
+        let request = || {
+            crate::testing::TestRequest::get("https://example.com/cgi-bin/my%20app/items")
+                .script_name("/cgi-bin/my app")
+        };
+
+        assert_eq!(
+            url_for(request(), "/items/3?edit=1"),
+            "https://example.com/cgi-bin/my%20app/items/3?edit=1"
+        );
+        assert_eq!(
+            url_for(request(), "login"),
+            "https://example.com/cgi-bin/my%20app/login"
+        );
+        assert_eq!(
+            url_for(request(), "/"),
+            "https://example.com/cgi-bin/my%20app/"
+        );
+        assert_eq!(
+            url_for(request(), "http://other.org/x"),
+            "http://other.org/x"
+        );
+        assert_eq!(url_for(request(), "//other.org/x"), "//other.org/x");
+        assert_eq!(
+            url_for(request(), "/users/Jürgen\r\n?q=a b&x=%2F"),
+            "https://example.com/cgi-bin/my%20app/users/J%C3%BCrgen%0D%0A?q=a%20b&x=%2F"
+        );
+
+        // A programme at the root, behind a server on another port
+        let request = crate::testing::TestRequest::get("/items").var("SERVER_PORT", "8080");
+        assert_eq!(url_for(request, "/a"), "http://localhost:8080/a");
+
+        // Not from a CGI request, so there's no SCRIPT_NAME or host
+        let request = http::Request::new(());
+        assert_eq!(request.url_for("/a"), "/a");
+    }
+}
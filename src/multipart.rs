@@ -0,0 +1,618 @@
+//! Parse `multipart/form-data` request bodies, i.e. HTML forms which upload files.
+//!
+//! If the body is already in memory (e.g. when using [`handle`](../fn.handle.html)), use
+//! [`parse`](fn.parse.html):
+//!
+//! ```rust
+//! extern crate cgi;
+//!
+//! cgi::cgi_main! { |request: cgi::Request| -> cgi::Response {
+//!     let parts = match cgi::multipart::parse(&request) {
+//!         Ok(parts) => parts,
+//!         Err(err) => return cgi::empty_response(err.status()),
+//!     };
+//!     let names: Vec<_> = parts.iter().filter_map(|p| p.filename.as_deref()).collect();
+//!     cgi::text_response(200, format!("Uploaded {}", names.join(", ")))
+//! } }
+//! ```
+//!
+//! For large uploads, read the body with a [`MultipartReader`](struct.MultipartReader.html) from
+//! [`handle_streaming_request`](../fn.handle_streaming_request.html), and have big parts
+//! written to temporary files rather than kept in memory:
+//!
+//! ```rust,ignore
+//! extern crate cgi;
+//!
+//! fn main() { cgi::handle_streaming_request(|request: cgi::StreamingRequest| {
+//!     let mut reader = cgi::multipart::MultipartReader::from_request(request)
+//!         .unwrap()
+//!         .spill_to_disk(1024 * 1024);
+//!     while let Some(part) = reader.next_part().unwrap() {
+//!         if let cgi::multipart::PartData::File(file) = part.data {
+//!             file.persist("/srv/uploads/latest").unwrap();
+//!         }
+//!     }
+//!     cgi::empty_response(204)
+//! })}
+//! ```
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::meta::content_type;
+use crate::CgiError;
+
+/// Refuse part header blocks bigger than this
+const MAX_HEADERS_LENGTH: usize = 16 * 1024;
+
+/// One field of the form
+#[derive(Debug)]
+pub struct Part {
+    /// The `name` from the `Content-Disposition` header, i.e. the form field's name
+    pub name: Option<String>,
+    /// The `filename` from the `Content-Disposition` header, for file uploads
+    pub filename: Option<String>,
+    /// The `Content-Type` of this part
+    pub content_type: Option<String>,
+    /// All the headers of this part
+    pub headers: http::HeaderMap,
+    /// The contents of this part
+    pub data: PartData,
+}
+
+impl Part {
+    /// The contents as a string, if it was kept in memory and is valid UTF-8
+    pub fn text(&self) -> Option<&str> {
+        self.data
+            .as_bytes()
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+}
+
+/// Where the contents of a [`Part`](struct.Part.html) are stored
+#[derive(Debug)]
+pub enum PartData {
+    /// The contents, in memory
+    Memory(Vec<u8>),
+    /// The contents were bigger than the [`spill_to_disk`](struct.MultipartReader.html#method.spill_to_disk)
+    /// threshold, so were written to this file
+    File(TempFile),
+}
+
+impl PartData {
+    /// The size of the contents, in bytes
+    pub fn len(&self) -> u64 {
+        match self {
+            PartData::Memory(bytes) => bytes.len() as u64,
+            PartData::File(file) => file.len(),
+        }
+    }
+
+    /// Whether the contents are empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The contents, if they are in memory
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            PartData::Memory(bytes) => Some(bytes),
+            PartData::File(_) => None,
+        }
+    }
+
+    /// Read the contents, wherever they are stored
+    pub fn reader(&self) -> io::Result<Box<dyn Read + '_>> {
+        match self {
+            PartData::Memory(bytes) => Ok(Box::new(bytes.as_slice())),
+            PartData::File(file) => Ok(Box::new(File::open(file.path())?)),
+        }
+    }
+}
+
+/// A temporary file, which is deleted when dropped, unless it is [`persist`](#method.persist)ed.
+///
+/// On unix, only the user the programme runs as can read & write it.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    size: u64,
+    persisted: bool,
+}
+
+impl TempFile {
+    fn create(dir: &Path) -> io::Result<(TempFile, File)> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        loop {
+            let nanos = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.subsec_nanos());
+            let path = dir.join(format!(
+                "rust-cgi-{}-{}-{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed),
+                nanos
+            ));
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            // The temporary directory is usually shared, so other users mustn't be able to read
+            // the uploads
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            match options.open(&path) {
+                Ok(file) => {
+                    let temp_file = TempFile {
+                        path,
+                        size: 0,
+                        persisted: false,
+                    };
+                    return Ok((temp_file, file));
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Where the file is
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The size of the file, in bytes
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    /// Whether the file is empty
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Move the file to `path`, so that it is kept
+    pub fn persist(mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        if std::fs::rename(&self.path, path.as_ref()).is_err() {
+            // e.g. the temporary directory is on a different filesystem
+            std::fs::copy(&self.path, path.as_ref())?;
+            std::fs::remove_file(&self.path)?;
+        }
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Where the contents of the current part are being written
+enum Sink {
+    Memory(Vec<u8>),
+    File(TempFile, File),
+}
+
+/// Parses a `multipart/form-data` body one part at a time, as it's read.
+#[derive(Debug)]
+pub struct MultipartReader<R> {
+    reader: R,
+    /// `\r\n--boundary`. The first boundary doesn't need the leading CRLF, so we start with it
+    /// already in `buf`.
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    state: State,
+    spill_threshold: Option<usize>,
+    temp_dir: PathBuf,
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Preamble,
+    AfterDelimiter,
+    Done,
+}
+
+fn invalid(msg: &str) -> CgiError {
+    CgiError::InvalidMultipart(msg.to_owned())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+impl<R: Read> MultipartReader<R> {
+    /// Read parts separated by `boundary` from `reader`
+    pub fn new(reader: R, boundary: &str) -> Self {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        MultipartReader {
+            reader,
+            delimiter,
+            buf: b"\r\n".to_vec(),
+            state: State::Preamble,
+            spill_threshold: None,
+            temp_dir: std::env::temp_dir(),
+        }
+    }
+
+    /// Read the parts from the body of this request.
+    ///
+    /// Fails if the request isn't `multipart/form-data`.
+    pub fn from_request(request: http::Request<R>) -> Result<Self, CgiError> {
+        let boundary =
+            boundary(&request).ok_or_else(|| invalid("Request is not multipart/form-data"))?;
+        Ok(MultipartReader::new(request.into_body(), &boundary))
+    }
+
+    /// Write parts bigger than `threshold` bytes to temporary files, rather than keeping them in
+    /// memory. See [`PartData::File`](enum.PartData.html#variant.File).
+    pub fn spill_to_disk(mut self, threshold: usize) -> Self {
+        self.spill_threshold = Some(threshold);
+        self
+    }
+
+    /// The directory temporary files are created in, `std::env::temp_dir()` by default
+    pub fn temp_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.temp_dir = dir.into();
+        self
+    }
+
+    /// Read some more of the body. `false` at the end of the body.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; 8192];
+        loop {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => return Ok(false),
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    return Ok(true);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Read until `needle` is in the buffer, and return its position
+    fn fill_until(&mut self, needle: &[u8]) -> Result<usize, CgiError> {
+        loop {
+            if let Some(pos) = find(&self.buf, needle) {
+                return Ok(pos);
+            }
+            if self.buf.len() > MAX_HEADERS_LENGTH {
+                return Err(invalid("Multipart headers too long"));
+            }
+            if !self.fill()? {
+                return Err(invalid("Multipart body ended unexpectedly"));
+            }
+        }
+    }
+
+    /// Move the first `len` bytes of the buffer to the sink
+    fn drain_to(&mut self, sink: &mut Sink, len: usize) -> Result<(), CgiError> {
+        if let (Sink::Memory(bytes), Some(threshold)) = (&*sink, self.spill_threshold) {
+            if bytes.len() + len > threshold {
+                let (mut temp_file, mut file) = TempFile::create(&self.temp_dir)?;
+                file.write_all(bytes)?;
+                temp_file.size = bytes.len() as u64;
+                *sink = Sink::File(temp_file, file);
+            }
+        }
+
+        match sink {
+            Sink::Memory(bytes) => bytes.extend_from_slice(&self.buf[..len]),
+            Sink::File(temp_file, file) => {
+                file.write_all(&self.buf[..len])?;
+                temp_file.size += len as u64;
+            }
+        }
+        self.buf.drain(..len);
+        Ok(())
+    }
+
+    /// The next part, or `None` after the last one
+    pub fn next_part(&mut self) -> Result<Option<Part>, CgiError> {
+        if self.state == State::Preamble {
+            // Skip anything before the first boundary
+            loop {
+                if let Some(pos) = find(&self.buf, &self.delimiter) {
+                    self.buf.drain(..pos + self.delimiter.len());
+                    break;
+                }
+                let keep = self.delimiter.len() - 1;
+                if self.buf.len() > keep {
+                    self.buf.drain(..self.buf.len() - keep);
+                }
+                if !self.fill()? {
+                    return Err(invalid("Multipart boundary not found"));
+                }
+            }
+            self.state = State::AfterDelimiter;
+        }
+
+        if self.state == State::Done {
+            return Ok(None);
+        }
+
+        // The rest of the boundary line is either `--` for the end, or (optional whitespace and)
+        // CRLF before the headers
+        while self.buf.len() < 2 {
+            if !self.fill()? {
+                return Err(invalid("Multipart body ended unexpectedly"));
+            }
+        }
+        if self.buf.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+        let line_end = self.fill_until(b"\r\n")?;
+        if self.buf[..line_end]
+            .iter()
+            .any(|b| *b != b' ' && *b != b'\t')
+        {
+            return Err(invalid("Invalid multipart boundary line"));
+        }
+        self.buf.drain(..line_end + 2);
+
+        let headers = if self.fill_until(b"\r\n")? == 0 {
+            // No headers at all
+            self.buf.drain(..2);
+            http::HeaderMap::new()
+        } else {
+            let headers_end = self.fill_until(b"\r\n\r\n")?;
+            let headers = parse_headers(&self.buf[..headers_end])?;
+            self.buf.drain(..headers_end + 4);
+            headers
+        };
+
+        let mut sink = Sink::Memory(Vec::new());
+        loop {
+            if let Some(pos) = find(&self.buf, &self.delimiter) {
+                self.drain_to(&mut sink, pos)?;
+                self.buf.drain(..self.delimiter.len());
+                break;
+            }
+            // Anything that can't be the start of a delimiter is part of the contents
+            let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+            self.drain_to(&mut sink, safe)?;
+            if !self.fill()? {
+                return Err(invalid("Multipart body ended unexpectedly"));
+            }
+        }
+
+        let data = match sink {
+            Sink::Memory(bytes) => PartData::Memory(bytes),
+            Sink::File(temp_file, mut file) => {
+                file.flush()?;
+                PartData::File(temp_file)
+            }
+        };
+
+        let header = |name: http::header::HeaderName| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+        let disposition = header(http::header::CONTENT_DISPOSITION)
+            .map(|d| split_params(&d).1)
+            .unwrap_or_default();
+        let param = |name: &str| {
+            disposition
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.clone())
+        };
+
+        Ok(Some(Part {
+            name: param("name"),
+            filename: param("filename"),
+            content_type: header(http::header::CONTENT_TYPE),
+            headers,
+            data,
+        }))
+    }
+}
+
+fn parse_headers(block: &[u8]) -> Result<http::HeaderMap, CgiError> {
+    let mut headers = http::HeaderMap::new();
+    for line in block.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let colon = line
+            .iter()
+            .position(|b| *b == b':')
+            .ok_or_else(|| invalid("Invalid multipart header"))?;
+        let name = http::header::HeaderName::from_bytes(&line[..colon])
+            .map_err(|_| invalid("Invalid multipart header name"))?;
+        let value = http::header::HeaderValue::from_bytes(crate::trim(&line[colon + 1..]))
+            .map_err(|_| invalid("Invalid multipart header value"))?;
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+/// Split a header value like `form-data; name="field"; filename="a.txt"` into the value and its
+/// (lowercased) parameters.
+///
+/// Browsers don't escape quoted strings with backslashes (they percent-encode `"` instead), so
+/// neither do we.
+fn split_params(value: &str) -> (&str, Vec<(String, String)>) {
+    let (main, mut rest) = match value.find(';') {
+        Some(i) => (&value[..i], &value[i + 1..]),
+        None => (value, ""),
+    };
+
+    let mut params = Vec::new();
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ';']);
+        let eq = match rest.find(['=', ';']) {
+            Some(i) if rest[i..].starts_with('=') => i,
+            Some(i) => {
+                // A parameter without a value
+                rest = &rest[i..];
+                continue;
+            }
+            None => break,
+        };
+        let name = rest[..eq].trim().to_ascii_lowercase();
+        rest = rest[eq + 1..].trim_start();
+
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            rest = quoted.get(end + 1..).unwrap_or("");
+            &quoted[..end]
+        } else {
+            let end = rest.find(';').unwrap_or(rest.len());
+            let value = rest[..end].trim();
+            rest = &rest[end..];
+            value
+        };
+        params.push((name, value.to_owned()));
+    }
+
+    (main.trim(), params)
+}
+
+/// The boundary of this `multipart/form-data` request, `None` if it isn't one.
+pub fn boundary<B>(request: &http::Request<B>) -> Option<String> {
+    let (mime, params) = split_params(content_type(request)?);
+    if !mime.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .into_iter()
+        .find(|(name, value)| name == "boundary" && !value.is_empty())
+        .map(|(_, value)| value)
+}
+
+/// Parse all the parts of this `multipart/form-data` request, in memory.
+pub fn parse<B: AsRef<[u8]>>(request: &http::Request<B>) -> Result<Vec<Part>, CgiError> {
+    let boundary =
+        boundary(request).ok_or_else(|| invalid("Request is not multipart/form-data"))?;
+    let mut reader = MultipartReader::new(request.body().as_ref(), &boundary);
+
+    let mut parts = Vec::new();
+    while let Some(part) = reader.next_part()? {
+        parts.push(part);
+    }
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHello\r\n--XyZ\r\nContent-Disposition: form-data; name=\"upload\"; filename=\"a b.txt\"\r\nContent-Type: text/plain\r\n\r\nline 1\r\n--XY not quite\r\nline 2\r\n--XyZ--\r\nepilogue";
+
+    fn request(body: &[u8]) -> http::Request<Vec<u8>> {
+        http::Request::builder()
+            .method("POST")
+            .header("Content-Type", "multipart/form-data; boundary=\"XyZ\"")
+            .body(body.to_vec())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_split_params() {
+        assert_eq!(
+            split_params("form-data; name=\"a;b\"; filename=x.txt; flag; Empty=\"\""),
+            (
+                "form-data",
+                vec![
+                    ("name".to_owned(), "a;b".to_owned()),
+                    ("filename".to_owned(), "x.txt".to_owned()),
+                    ("empty".to_owned(), "".to_owned()),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let parts = parse(&request(BODY)).unwrap();
+        assert_eq!(parts.len(), 2);
+
+        assert_eq!(parts[0].name.as_deref(), Some("title"));
+        assert_eq!(parts[0].filename, None);
+        assert_eq!(parts[0].text(), Some("Hello"));
+
+        assert_eq!(parts[1].name.as_deref(), Some("upload"));
+        assert_eq!(parts[1].filename.as_deref(), Some("a b.txt"));
+        assert_eq!(parts[1].content_type.as_deref(), Some("text/plain"));
+        assert_eq!(parts[1].text(), Some("line 1\r\n--XY not quite\r\nline 2"));
+    }
+
+    #[test]
+    fn test_small_reads() {
+        /// Only ever returns one byte, to check delimiters split across reads are found
+        struct OneByte<'a>(&'a [u8]);
+        impl Read for OneByte<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.0[..self.0.len().min(1)].as_ref().read(buf)?;
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let mut reader = MultipartReader::new(OneByte(BODY), "XyZ");
+        assert_eq!(reader.next_part().unwrap().unwrap().text(), Some("Hello"));
+        assert_eq!(reader.next_part().unwrap().unwrap().data.len(), 30);
+        assert!(reader.next_part().unwrap().is_none());
+        assert!(reader.next_part().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_spill_to_disk() {
+        let mut reader = MultipartReader::new(BODY, "XyZ").spill_to_disk(10);
+
+        let small = reader.next_part().unwrap().unwrap();
+        assert_eq!(small.text(), Some("Hello"));
+
+        let big = reader.next_part().unwrap().unwrap();
+        let path = match &big.data {
+            PartData::File(file) => file.path().to_owned(),
+            PartData::Memory(_) => panic!("Part should have been written to a file"),
+        };
+        let mut contents = String::new();
+        big.data
+            .reader()
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "line 1\r\n--XY not quite\r\nline 2");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        drop(big);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_invalid() {
+        // Not multipart
+        let req = http::Request::builder()
+            .header("Content-Type", "text/plain")
+            .body(vec![])
+            .unwrap();
+        assert!(matches!(parse(&req), Err(CgiError::InvalidMultipart(_))));
+
+        // No closing boundary
+        assert!(parse(&request(b"--XyZ\r\n\r\nunfinished")).is_err());
+        // No boundary at all
+        assert!(parse(&request(b"just some text")).is_err());
+        // Header without a colon
+        assert!(parse(&request(b"--XyZ\r\nbroken\r\n\r\nx\r\n--XyZ--")).is_err());
+    }
+}
@@ -0,0 +1,530 @@
+use std::net::IpAddr;
+
+use crate::RequestExt;
+
+/// Which proxies to believe about the client, when the programme is behind a load balancer or
+/// reverse proxy.
+///
+/// Behind a proxy, `REMOTE_ADDR` is the proxy's address. Proxies add the client's address, and
+/// the scheme & host it asked for, to a `Forwarded` header (RFC 7239), or to `X-Forwarded-For`,
+/// `X-Forwarded-Proto` & `X-Forwarded-Host` headers. Anyone can send these headers though, so
+/// they're only used when the request came from a proxy in one of the trusted networks. Nothing
+/// is trusted by default.
+///
+/// The hops are followed back from `REMOTE_ADDR`, for as long as they're trusted proxies. The
+/// first untrusted address is the client.
+///
+/// ```rust
+/// extern crate cgi;
+///
+/// cgi::cgi_main! { |request: cgi::Request| -> cgi::Response {
+///     let proxies = cgi::TrustedProxies::new().trust("10.0.0.0/8").trust("::1");
+///     let client = proxies.client_info(&request);
+///     cgi::text_response(200, format!("Hello {:?} on {}", client.ip, client.scheme))
+/// } }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrustedProxies {
+    networks: Vec<Network>,
+    x_forwarded: bool,
+}
+
+/// Who made the request, from [`TrustedProxies::client_info`](struct.TrustedProxies.html#method.client_info)
+/// or [`RequestExt::client_info`](trait.RequestExt.html#tymethod.client_info).
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct ClientInfo {
+    /// The client's IP address, if it's known
+    pub ip: Option<IpAddr>,
+    /// The scheme the client used, `http` or `https`
+    pub scheme: http::uri::Scheme,
+    /// The host (and port, if it's not the default) the client asked for, if it's known
+    pub host: Option<String>,
+}
+
+/// An IP network, e.g. `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl TrustedProxies {
+    /// Trust no proxies, so the client is always `REMOTE_ADDR`
+    pub fn new() -> TrustedProxies {
+        TrustedProxies::default()
+    }
+
+    /// Trust proxies in this network, either an address (`192.0.2.1`, `::1`) or a CIDR range
+    /// (`10.0.0.0/8`, `fd00::/8`).
+    ///
+    /// # Panics
+    ///
+    /// If `network` isn't an address or CIDR range.
+    pub fn trust(mut self, network: &str) -> TrustedProxies {
+        match parse_network(network) {
+            Some(network) => self.networks.push(network),
+            None => panic!("Invalid trusted proxy network: {:?}", network),
+        }
+        self
+    }
+
+    /// Use the `X-Forwarded-For`, `X-Forwarded-Proto` & `X-Forwarded-Host` headers, instead of
+    /// `Forwarded`.
+    ///
+    /// Only one kind is used, so a client can't add the kind your proxies don't send. Each trusted
+    /// proxy must add one entry to `X-Forwarded-Proto` & `X-Forwarded-Host` for each one it adds
+    /// to `X-Forwarded-For` (or overwrite them, if there's only one proxy). If the number of
+    /// entries doesn't match the number of trusted proxies, the client added some, so they're
+    /// ignored. A proxy which only sets them when they aren't already there passes on what the
+    /// client made up.
+    pub fn x_forwarded(mut self) -> TrustedProxies {
+        self.x_forwarded = true;
+        self
+    }
+
+    /// Whether `addr` is in one of the trusted networks
+    pub fn is_trusted(&self, addr: IpAddr) -> bool {
+        let addr = unmap(addr);
+        self.networks.iter().any(|network| network.contains(addr))
+    }
+
+    /// The client who made this request, according to the trusted proxies
+    pub fn client_info<B>(&self, request: &http::Request<B>) -> ClientInfo {
+        let mut client = direct_client_info(request);
+        let mut ip = match client.ip {
+            Some(ip) => ip,
+            None => return client,
+        };
+
+        let mut hops = if self.x_forwarded {
+            self.x_forwarded_hops(request.headers(), ip)
+        } else {
+            forwarded_hops(request.headers())
+        };
+        while self.is_trusted(ip) {
+            let hop = match hops.pop() {
+                Some(hop) => hop,
+                None => break,
+            };
+            if let Some(scheme) = hop.proto.and_then(|proto| parse_scheme(&proto)) {
+                client.scheme = scheme;
+            }
+            if let Some(host) = hop
+                .host
+                .filter(|host| host.parse::<http::uri::Authority>().is_ok())
+            {
+                client.host = Some(host);
+            }
+            // The proxy didn't say who connected to it (e.g. `for=unknown`), so that's as far
+            // back as we can go
+            match hop.addr {
+                Some(addr) => ip = addr,
+                None => break,
+            }
+        }
+        client.ip = Some(ip);
+        client
+    }
+
+    /// How many of the hops (from the last) were added by trusted proxies, starting with the one
+    /// at `addr`
+    fn trusted_count(&self, mut addr: IpAddr, hops: &[Hop]) -> usize {
+        let mut count = 0;
+        for hop in hops.iter().rev() {
+            if !self.is_trusted(addr) {
+                break;
+            }
+            count += 1;
+            match hop.addr {
+                Some(hop_addr) => addr = hop_addr,
+                None => break,
+            }
+        }
+        count
+    }
+
+    /// The hops in the `X-Forwarded-*` headers, client first, from the proxy at `remote_addr`.
+    /// The proto & host are only used if there's one for each trusted proxy.
+    fn x_forwarded_hops(&self, headers: &http::HeaderMap, remote_addr: IpAddr) -> Vec<Hop> {
+        let mut hops: Vec<Hop> = header_list(headers, "x-forwarded-for")
+            .iter()
+            .map(|addr| Hop {
+                addr: parse_node(addr),
+                ..Hop::default()
+            })
+            .collect();
+        let trusted = self.trusted_count(remote_addr, &hops);
+        let first = hops.len() - trusted;
+
+        let protos = header_list(headers, "x-forwarded-proto");
+        if protos.len() == trusted {
+            for (hop, proto) in hops[first..].iter_mut().zip(protos) {
+                hop.proto = Some(proto);
+            }
+        }
+        let hosts = header_list(headers, "x-forwarded-host");
+        if hosts.len() == trusted {
+            for (hop, host) in hops[first..].iter_mut().zip(hosts) {
+                hop.host = Some(host);
+            }
+        }
+        hops
+    }
+
+    /// Add the [`ClientInfo`](struct.ClientInfo.html) to the request's extensions, for
+    /// [`RequestExt::client_info`](trait.RequestExt.html#tymethod.client_info) &
+    /// [`RequestExt::url_for`](trait.RequestExt.html#tymethod.url_for) to use.
+    pub fn apply<B>(&self, request: &mut http::Request<B>) {
+        let client = self.client_info(request);
+        request.extensions_mut().insert(client);
+    }
+}
+
+/// The client, if there are no proxies in the way
+pub(crate) fn direct_client_info<B>(request: &http::Request<B>) -> ClientInfo {
+    let meta = request.cgi_meta();
+    let https = match request.uri().scheme() {
+        Some(scheme) => scheme == &http::uri::Scheme::HTTPS,
+        None => matches!(meta, Some(meta) if meta.https),
+    };
+    let host = request
+        .uri()
+        .authority()
+        .map(|authority| authority.as_str().to_owned())
+        .or_else(|| {
+            request
+                .headers()
+                .get(http::header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .map(str::to_owned)
+        });
+    ClientInfo {
+        ip: meta.and_then(|meta| meta.remote_addr),
+        scheme: if https {
+            http::uri::Scheme::HTTPS
+        } else {
+            http::uri::Scheme::HTTP
+        },
+        host,
+    }
+}
+
+impl Network {
+    fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn parse_network(network: &str) -> Option<Network> {
+    let mut parts = network.trim().splitn(2, '/');
+    let addr = unmap(parts.next()?.parse().ok()?);
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match parts.next() {
+        Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= max)?,
+        None => max,
+    };
+    Some(Network { addr, prefix })
+}
+
+/// IPv4 addresses can arrive as IPv4-mapped IPv6 addresses (`::ffff:192.0.2.1`)
+fn unmap(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => {
+                IpAddr::V4((u32::from(high) << 16 | u32::from(low)).into())
+            }
+            _ => addr,
+        },
+        addr => addr,
+    }
+}
+
+fn parse_scheme(proto: &str) -> Option<http::uri::Scheme> {
+    if proto.eq_ignore_ascii_case("https") {
+        Some(http::uri::Scheme::HTTPS)
+    } else if proto.eq_ignore_ascii_case("http") {
+        Some(http::uri::Scheme::HTTP)
+    } else {
+        None
+    }
+}
+
+/// What one proxy said about the hop before it
+#[derive(Debug, Default, PartialEq)]
+struct Hop {
+    addr: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+/// A node (RFC 7239 § 6), e.g. `192.0.2.1`, `192.0.2.1:8080`, `[2001:db8::1]:8080` or
+/// `2001:db8::1`. Obfuscated & `unknown` nodes have no address.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok().map(unmap);
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.rsplit_once(':')?.0.parse().ok())
+        .map(unmap)
+}
+
+/// All the values of a header, split on commas
+fn header_list(headers: &http::HeaderMap, name: &str) -> Vec<String> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+/// The hops in the `Forwarded` headers, client first
+fn forwarded_hops(headers: &http::HeaderMap) -> Vec<Hop> {
+    let mut hops = Vec::new();
+    for value in headers.get_all(http::header::FORWARDED) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        for element in split_unquoted(value, ',') {
+            let mut hop = Hop::default();
+            for pair in split_unquoted(&element, ';') {
+                let mut parts = pair.splitn(2, '=');
+                let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+                let value = parts.next().unwrap_or_default().trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value)
+                    .to_owned();
+                match name.as_str() {
+                    "for" => hop.addr = parse_node(&value),
+                    "proto" => hop.proto = Some(value),
+                    "host" => hop.host = Some(value),
+                    _ => {}
+                }
+            }
+            hops.push(hop);
+        }
+    }
+    hops
+}
+
+/// Split on `separator`, except inside quoted strings
+fn split_unquoted(value: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        let part = parts.last_mut().expect("there's always a part");
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(String::new());
+            continue;
+        }
+        part.push(c);
+    }
+    parts.retain(|part| !part.trim().is_empty());
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRequest;
+
+    /// Run the request, and return the client info
+    fn client_info(proxies: &TrustedProxies, request: TestRequest) -> ClientInfo {
+        let mut client = None;
+        request.run(|request| {
+            client = Some(proxies.client_info(&request));
+            crate::empty_response(200)
+        });
+        client.unwrap()
+    }
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn test_networks() {
+        let proxies = TrustedProxies::new()
+            .trust("10.0.0.0/8")
+            .trust("192.0.2.1")
+            .trust("fd00::/8");
+        assert!(proxies.is_trusted("10.1.2.3".parse().unwrap()));
+        assert!(proxies.is_trusted("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(proxies.is_trusted("192.0.2.1".parse().unwrap()));
+        assert!(!proxies.is_trusted("192.0.2.2".parse().unwrap()));
+        assert!(!proxies.is_trusted("11.0.0.1".parse().unwrap()));
+        assert!(proxies.is_trusted("fd12::1".parse().unwrap()));
+        assert!(!proxies.is_trusted("fe80::1".parse().unwrap()));
+
+        assert!(TrustedProxies::new()
+            .trust("0.0.0.0/0")
+            .is_trusted("8.8.8.8".parse().unwrap()));
+        assert!(!TrustedProxies::new().is_trusted("127.0.0.1".parse().unwrap()));
+        assert_eq!(parse_network("10.0.0.0/33"), None);
+        assert_eq!(parse_network("proxy.example"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid trusted proxy network")]
+    fn test_invalid_network() {
+        TrustedProxies::new().trust("10.0.0.0/x");
+    }
+
+    #[test]
+    fn test_parse_node() {
+        assert_eq!(parse_node("192.0.2.60"), ip("192.0.2.60"));
+        assert_eq!(parse_node("192.0.2.60:4711"), ip("192.0.2.60"));
+        assert_eq!(
+            parse_node("[2001:db8:cafe::17]:4711"),
+            ip("2001:db8:cafe::17")
+        );
+        assert_eq!(parse_node("2001:db8:cafe::17"), ip("2001:db8:cafe::17"));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let proxies = TrustedProxies::new().trust("10.0.0.0/8");
+        let request = || {
+            TestRequest::get("/")
+                .remote_addr("10.0.0.2")
+                .header("Host", "internal")
+                .header(
+                    "Forwarded",
+                    "for=198.51.100.7, for=\"[2001:db8::1]:4711\";proto=https;host=\"example.com\"",
+                )
+                .header("Forwarded", "for=10.0.0.1")
+        };
+
+        // 10.0.0.2 & 10.0.0.1 are trusted, 2001:db8::1 isn't, so it's the client, and it can't
+        // tell us who the first address is
+        assert_eq!(
+            client_info(&proxies, request()),
+            ClientInfo {
+                ip: ip("2001:db8::1"),
+                scheme: http::uri::Scheme::HTTPS,
+                host: Some("example.com".to_owned()),
+            }
+        );
+
+        // Not from a trusted proxy, so the header is ignored
+        assert_eq!(
+            client_info(&proxies, request().remote_addr("192.0.2.9")),
+            ClientInfo {
+                ip: ip("192.0.2.9"),
+                scheme: http::uri::Scheme::HTTP,
+                host: Some("internal".to_owned()),
+            }
+        );
+
+        // Trusting everyone, we can go all the way back
+        let everyone = TrustedProxies::new().trust("0.0.0.0/0").trust("::/0");
+        assert_eq!(client_info(&everyone, request()).ip, ip("198.51.100.7"));
+
+        // A trusted proxy which doesn't know who connected to it
+        let request = TestRequest::get("/")
+            .remote_addr("10.0.0.2")
+            .header("Forwarded", "for=unknown;proto=https");
+        let client = client_info(&proxies, request);
+        assert_eq!(client.ip, ip("10.0.0.2"));
+        assert_eq!(client.scheme, http::uri::Scheme::HTTPS);
+    }
+
+    #[test]
+    fn test_x_forwarded() {
+        let proxies = TrustedProxies::new().trust("10.0.0.0/8").x_forwarded();
+        let request = TestRequest::get("/")
+            .remote_addr("10.0.0.2")
+            .header("X-Forwarded-For", "203.0.113.5, 198.51.100.7, 10.0.0.1")
+            .header("X-Forwarded-Proto", "https, http")
+            .header("X-Forwarded-Host", "example.com:8443, internal:8080")
+            .header("Forwarded", "for=192.0.2.1");
+        assert_eq!(
+            client_info(&proxies, request),
+            ClientInfo {
+                ip: ip("198.51.100.7"),
+                scheme: http::uri::Scheme::HTTPS,
+                host: Some("example.com:8443".to_owned()),
+            }
+        );
+
+        // The Forwarded header isn't used
+        let request = TestRequest::get("/")
+            .remote_addr("10.0.0.2")
+            .header("Forwarded", "for=192.0.2.1");
+        assert_eq!(client_info(&proxies, request).ip, ip("10.0.0.2"));
+
+        // The client set X-Forwarded-Host & -Proto itself, and the proxy added to them, so
+        // there's more than one for each trusted proxy
+        let request = TestRequest::get("/")
+            .remote_addr("10.0.0.2")
+            .header("Host", "internal")
+            .header("X-Forwarded-For", "198.51.100.7")
+            .header("X-Forwarded-Proto", "https, http")
+            .header("X-Forwarded-Host", "evil.example, example.com");
+        assert_eq!(
+            client_info(&proxies, request),
+            ClientInfo {
+                ip: ip("198.51.100.7"),
+                scheme: http::uri::Scheme::HTTP,
+                host: Some("internal".to_owned()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let proxies = TrustedProxies::new().trust("127.0.0.1").x_forwarded();
+        let response = TestRequest::get("/cgi-bin/app/items")
+            .script_name("/cgi-bin/app")
+            .header("X-Forwarded-For", "198.51.100.7")
+            .header("X-Forwarded-Proto", "https")
+            .header("X-Forwarded-Host", "example.com")
+            .run(|mut request| {
+                proxies.apply(&mut request);
+                let client = request.client_info();
+                crate::text_response(200, format!("{:?} {}", client.ip, request.url_for("/a")))
+            });
+        assert_eq!(
+            response.text(),
+            "Some(198.51.100.7) https://example.com/cgi-bin/app/a"
+        );
+
+        // Without proxies, it's the direct client
+        let response = TestRequest::get("/")
+            .remote_addr("192.0.2.1")
+            .header("X-Forwarded-For", "198.51.100.7")
+            .run(|request| crate::text_response(200, format!("{:?}", request.client_info().ip)));
+        assert_eq!(response.text(), "Some(192.0.2.1)");
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+
+use crate::form::percent_decode_path;
+use crate::{empty_response, Request, RequestExt, Response};
+
+type Handler = Box<dyn Fn(Request) -> Response + Send + Sync>;
+
+/// Dispatch requests to different functions, on the method and the path.
+///
+/// Paths are matched against `PATH_INFO`, i.e. the part of the path after the script
+/// (`SCRIPT_NAME`), so the routes don't change when the script is moved. A pattern is made of
+/// `/` separated segments, which are either:
+///
+/// * text, which must match exactly, e.g. `/users`
+/// * a parameter, e.g. `/users/:id`, which matches any one segment
+/// * a wildcard, e.g. `/files/*path`, which matches the rest of the path (which can be empty)
+///
+/// Get the values of the parameters & wildcards with
+/// [`RequestExt::route_param`](trait.RequestExt.html#tymethod.route_param). The first matching
+/// route is used. If no route matches the path, a `404 Not Found` is returned, and if routes
+/// match the path, but not the method, a `405 Method Not Allowed`. A trailing `/` is ignored, but
+/// a path with an empty segment (e.g. `/users//1`) doesn't match any route.
+///
+/// Pass [`into_handler`](#method.into_handler) to [`handle`](fn.handle.html),
+/// [`cgi_main!`](macro.cgi_main.html) etc. to run the router, as those take a function:
+///
+/// ```rust
+/// extern crate cgi;
+/// use cgi::RequestExt;
+///
+/// fn app() -> cgi::Router {
+///     cgi::Router::new()
+///         .get("/", |_| cgi::text_response(200, "Home"))
+///         .get("/users/:id", |request| {
+///             cgi::text_response(200, format!("User {}", request.route_param("id").unwrap()))
+///         })
+///         .post("/users", |_| cgi::empty_response(201))
+/// }
+///
+/// cgi::cgi_main! { app().into_handler() }
+/// ```
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+struct Route {
+    method: Option<http::Method>,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Param(String),
+    Wildcard(String),
+}
+
+/// The parameters of the route which matched, in the request's extensions
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RouteParams(HashMap<String, String>);
+
+impl RouteParams {
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+}
+
+impl Router {
+    /// A router with no routes (so everything is a `404 Not Found`)
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Send requests with this method & path pattern to `handler`
+    pub fn route<F>(mut self, method: http::Method, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method: Some(method),
+            segments: parse_pattern(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Send requests for this path pattern to `handler`, whatever the method
+    pub fn any<F>(mut self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method: None,
+            segments: parse_pattern(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Send `GET` requests (and `HEAD` requests, without the body) for this path pattern to
+    /// `handler`
+    pub fn get<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(http::Method::GET, pattern, handler)
+    }
+
+    /// Send `POST` requests for this path pattern to `handler`
+    pub fn post<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(http::Method::POST, pattern, handler)
+    }
+
+    /// Send `PUT` requests for this path pattern to `handler`
+    pub fn put<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(http::Method::PUT, pattern, handler)
+    }
+
+    /// Send `PATCH` requests for this path pattern to `handler`
+    pub fn patch<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(http::Method::PATCH, pattern, handler)
+    }
+
+    /// Send `DELETE` requests for this path pattern to `handler`
+    pub fn delete<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(http::Method::DELETE, pattern, handler)
+    }
+
+    /// Pass the request to the first route which matches it
+    pub fn dispatch(&self, mut request: Request) -> Response {
+        let path = match request.cgi_meta() {
+            Some(meta) => meta.path_info.clone().unwrap_or_default(),
+            None => String::from_utf8_lossy(&percent_decode_path(request.uri().path().as_bytes()))
+                .into_owned(),
+        };
+        let path = path.strip_prefix('/').unwrap_or(&path);
+        let path = path.strip_suffix('/').unwrap_or(path);
+        let path: Vec<&str> = if path.is_empty() {
+            Vec::new()
+        } else {
+            path.split('/').collect()
+        };
+        // `/a//b` isn't the same path as `/a/b`
+        if path.contains(&"") {
+            return empty_response(404);
+        }
+        let is_head = request.method() == http::Method::HEAD;
+
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            let params = match match_segments(&route.segments, &path) {
+                Some(params) => params,
+                None => continue,
+            };
+            let method_matches = match &route.method {
+                None => true,
+                Some(method) => {
+                    method == request.method() || (is_head && method == http::Method::GET)
+                }
+            };
+            if !method_matches {
+                if let Some(method) = &route.method {
+                    if !allowed.contains(method) {
+                        allowed.push(method.clone());
+                    }
+                }
+                continue;
+            }
+
+            request.extensions_mut().insert(RouteParams(params));
+            let mut response = (route.handler)(request);
+            if is_head && route.method.as_ref() == Some(&http::Method::GET) {
+                response.body_mut().clear();
+            }
+            return response;
+        }
+
+        if allowed.is_empty() {
+            return empty_response(404);
+        }
+        // GET routes serve HEAD requests too
+        if allowed.contains(&http::Method::GET) && !allowed.contains(&http::Method::HEAD) {
+            allowed.push(http::Method::HEAD);
+        }
+        let allow = allowed
+            .iter()
+            .map(http::Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut response = empty_response(405);
+        response.headers_mut().insert(
+            http::header::ALLOW,
+            http::HeaderValue::from_str(&allow).expect("method names are valid header values"),
+        );
+        response
+    }
+
+    /// A function which dispatches requests with this router, to pass to
+    /// [`handle`](fn.handle.html), [`cgi_main!`](macro.cgi_main.html), or
+    /// [`fastcgi::serve`](fastcgi/fn.serve.html).
+    pub fn into_handler(self) -> impl Fn(Request) -> Response + Send + Sync + 'static {
+        move |request| self.dispatch(request)
+    }
+}
+
+impl std::fmt::Debug for Router {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(
+                self.routes
+                    .iter()
+                    .map(|route| (&route.method, &route.segments)),
+            )
+            .finish()
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    pattern
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|segment| {
+            if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name.to_owned())
+            } else if let Some(name) = segment.strip_prefix('*') {
+                Segment::Wildcard(name.to_owned())
+            } else {
+                Segment::Text(segment.to_owned())
+            }
+        })
+        .collect()
+}
+
+/// If the path matches the pattern, the values of the parameters
+fn match_segments(pattern: &[Segment], path: &[&str]) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    for (i, segment) in pattern.iter().enumerate() {
+        match segment {
+            Segment::Wildcard(name) => {
+                params.insert(name.clone(), path.get(i..).unwrap_or_default().join("/"));
+                return Some(params);
+            }
+            Segment::Text(text) if path.get(i) == Some(&text.as_str()) => {}
+            Segment::Param(name) => {
+                params.insert(name.clone(), (*path.get(i)?).to_owned());
+            }
+            Segment::Text(_) => return None,
+        }
+    }
+    if path.len() == pattern.len() {
+        Some(params)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRequest;
+
+    fn app() -> Router {
+        let echo = |request: Request| {
+            let param = |name| request.route_param(name).unwrap_or("-").to_owned();
+            crate::text_response(
+                200,
+                format!("{} {} {}", request.method(), param("id"), param("path")),
+            )
+        };
+        Router::new()
+            .get("/", |_| crate::text_response(200, "home"))
+            .get("/users/:id", echo)
+            .delete("/users/:id", echo)
+            .any("/files/*path", echo)
+    }
+
+    #[test]
+    fn test_parse_pattern() {
+        assert_eq!(
+            parse_pattern("/users/:id/files/*path"),
+            vec![
+                Segment::Text("users".to_owned()),
+                Segment::Param("id".to_owned()),
+                Segment::Text("files".to_owned()),
+                Segment::Wildcard("path".to_owned()),
+            ]
+        );
+        assert_eq!(parse_pattern("/"), vec![]);
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let router = app();
+        let run = |request: TestRequest| {
+            request
+                .script_name("/cgi-bin/app")
+                .run(|r| router.dispatch(r))
+        };
+
+        let response = run(TestRequest::get("/cgi-bin/app"));
+        assert_eq!(response.text(), "home");
+        let response = run(TestRequest::get("/cgi-bin/app/"));
+        assert_eq!(response.text(), "home");
+
+        let response = run(TestRequest::get("/cgi-bin/app/users/42"));
+        assert_eq!(response.text(), "GET 42 -");
+        let response = run(TestRequest::new("DELETE", "/cgi-bin/app/users/J%C3%BCrgen"));
+        assert_eq!(response.text(), "DELETE Jürgen -");
+
+        let response = run(TestRequest::post("/cgi-bin/app/files/a/b.txt"));
+        assert_eq!(response.text(), "POST - a/b.txt");
+        let response = run(TestRequest::get("/cgi-bin/app/files"));
+        assert_eq!(response.text(), "GET - ");
+    }
+
+    #[test]
+    fn test_not_found_and_not_allowed() {
+        let router = app();
+        let run = |request: TestRequest| request.run(|r| router.dispatch(r));
+
+        assert_eq!(run(TestRequest::get("/users")).status(), 404);
+        assert_eq!(run(TestRequest::get("/users/1/more")).status(), 404);
+        assert_eq!(run(TestRequest::get("/nothing")).status(), 404);
+        assert_eq!(run(TestRequest::get("/users//1")).status(), 404);
+        assert_eq!(run(TestRequest::get("//users/1")).status(), 404);
+        assert_eq!(run(TestRequest::get("/files//a")).status(), 404);
+        assert_eq!(run(TestRequest::get("/users/1/")).status(), 200);
+
+        let response = run(TestRequest::post("/users/1"));
+        assert_eq!(response.status(), 405);
+        assert_eq!(response.headers()["allow"], "GET, DELETE, HEAD");
+    }
+
+    #[test]
+    fn test_head() {
+        let response = TestRequest::new("HEAD", "/users/1").run(app().into_handler());
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-length"], "8");
+        assert_eq!(response.body(), b"");
+    }
+
+    #[test]
+    fn test_without_cgi_meta() {
+        let request = http::Request::builder()
+            .uri("/users/a%20b")
+            .body(Vec::new())
+            .unwrap();
+        let response = app().dispatch(request);
+        assert_eq!(response.body(), b"GET a b -");
+    }
+}
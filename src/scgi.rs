@@ -0,0 +1,236 @@
+//! Run a CGI handler as a persistent [SCGI](https://python.ca/scgi/protocol.txt) server.
+//!
+//! The same `Fn(Request) -> Response` function you would pass to [`handle`](../fn.handle.html)
+//! can be served to an SCGI webserver (e.g. lighttpd's `mod_scgi`, or uwsgi). The SCGI headers
+//! of each request are used exactly like the CGI environmental variables are.
+//!
+//! The request body is read within the [`Limits`](../struct.Limits.html) you give, except for
+//! the read timeout, which is up to the webserver.
+//!
+//! ```rust,no_run
+//! extern crate cgi;
+//!
+//! fn main() {
+//!     let listener = std::net::TcpListener::bind("127.0.0.1:4000").unwrap();
+//!     let limits = cgi::Limits::new().max_body_size(16 * 1024 * 1024);
+//!     cgi::scgi::serve(
+//!         listener,
+//!         |request: cgi::Request| -> cgi::Response { cgi::text_response(200, "Hello World") },
+//!         &limits,
+//!     )
+//!     .unwrap();
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+
+use crate::limits::read_exactly;
+use crate::{
+    error_response, parse_request, serialize_response, serve_incoming, CgiError, Limits, Request,
+    Response,
+};
+
+/// Refuse header blocks bigger than this, rather than allocating whatever we're told to
+const MAX_HEADERS_LENGTH: usize = 1024 * 1024;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// Read the netstring (`<length>:<bytes>,`) containing the request headers
+fn read_netstring<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut length: usize = 0;
+    let mut digits = 0;
+    loop {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        match byte[0] {
+            b':' if digits > 0 => break,
+            b @ b'0'..=b'9' => {
+                length = length * 10 + (b - b'0') as usize;
+                digits += 1;
+                if length > MAX_HEADERS_LENGTH {
+                    return Err(invalid("SCGI headers too long"));
+                }
+            }
+            _ => return Err(invalid("Invalid SCGI netstring length")),
+        }
+    }
+
+    let mut contents = vec![0; length + 1];
+    reader.read_exact(&mut contents)?;
+    if contents.pop() != Some(b',') {
+        return Err(invalid("SCGI netstring not terminated with ','"));
+    }
+
+    Ok(contents)
+}
+
+/// Split the header block into the CGI environmental variables
+fn parse_headers(headers: &[u8]) -> io::Result<HashMap<String, String>> {
+    let fields: Vec<&[u8]> = match headers.strip_suffix(&[0]) {
+        Some(headers) => headers.split(|b| *b == 0).collect(),
+        None if headers.is_empty() => Vec::new(),
+        None => return Err(invalid("SCGI headers not NUL terminated")),
+    };
+    let pairs = fields.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return Err(invalid("SCGI header without a value"));
+    }
+
+    // The spec requires this to be the first header
+    if fields.first() != Some(&&b"CONTENT_LENGTH"[..]) {
+        return Err(invalid("First SCGI header must be CONTENT_LENGTH"));
+    }
+
+    Ok(pairs
+        .map(|pair| {
+            (
+                String::from_utf8_lossy(pair[0]).into_owned(),
+                String::from_utf8_lossy(pair[1]).into_owned(),
+            )
+        })
+        .collect())
+}
+
+/// Serve the single SCGI request on this connection.
+///
+/// The request is read, passed to `handler`, and the response is written back. If the body is
+/// bigger than `limits` allow, or shorter than `CONTENT_LENGTH`, an error response is sent
+/// instead. This is useful if you are accepting connections yourself, otherwise see
+/// [`serve`](fn.serve.html).
+pub fn handle_connection<S, F>(mut stream: S, handler: &F, limits: &Limits) -> io::Result<()>
+where
+    S: Read + Write,
+    F: Fn(Request) -> Response,
+{
+    let env_vars = parse_headers(&read_netstring(&mut stream)?)?;
+
+    let body = limits
+        .check_length(&env_vars)
+        .and_then(|length| read_exactly(&mut stream, length));
+    let response = match body.and_then(|body| parse_request(env_vars, body)) {
+        Ok(request) => handler(request),
+        Err(CgiError::Io(err)) => return Err(err),
+        Err(err) => error_response(&err),
+    };
+
+    let output = serialize_response(response);
+
+    stream.write_all(&output)?;
+    stream.flush()
+}
+
+/// Accept SCGI connections from `listener` forever, passing each request to `handler`.
+///
+/// Every connection is handled on its own thread, like
+/// [`handle_connection`](fn.handle_connection.html) does. Errors on one connection are printed to
+/// stderr, and don't stop the server.
+pub fn serve<F>(listener: TcpListener, handler: F, limits: &Limits) -> io::Result<()>
+where
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    serve_incoming(
+        listener.incoming(),
+        (handler, *limits),
+        |stream, (handler, limits)| handle_connection(stream, handler, limits),
+        "SCGI",
+    )
+}
+
+/// Same as [`serve`](fn.serve.html), but for a Unix domain socket.
+#[cfg(unix)]
+pub fn serve_unix<F>(
+    listener: std::os::unix::net::UnixListener,
+    handler: F,
+    limits: &Limits,
+) -> io::Result<()>
+where
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    serve_incoming(
+        listener.incoming(),
+        (handler, *limits),
+        |stream, (handler, limits)| handle_connection(stream, handler, limits),
+        "SCGI",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Duplex;
+
+    fn run_within(input: &[u8], limits: &Limits) -> io::Result<String> {
+        let mut conn = Duplex::new(input.to_vec());
+        handle_connection(
+            &mut conn,
+            &|req: Request| {
+                crate::text_response(
+                    200,
+                    format!(
+                        "{} {} {}",
+                        req.method(),
+                        req.uri(),
+                        String::from_utf8_lossy(req.body())
+                    ),
+                )
+            },
+            limits,
+        )?;
+        Ok(String::from_utf8(conn.output).unwrap())
+    }
+
+    fn run(input: &[u8]) -> io::Result<String> {
+        run_within(input, &Limits::default())
+    }
+
+    #[test]
+    fn test_request() {
+        let headers = b"CONTENT_LENGTH\x0027\x00SCGI\x001\x00REQUEST_METHOD\x00POST\x00REQUEST_URI\x00/deepthought\x00";
+        let mut input = format!("{}:", headers.len()).into_bytes();
+        input.extend_from_slice(headers);
+        input.extend_from_slice(b",What is the answer to life?");
+
+        assert_eq!(
+            run(&input).unwrap(),
+            "Status: 200 OK\ncontent-length: 45\ncontent-type: text/plain; charset=utf-8\n\nPOST /deepthought What is the answer to life?"
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        // Not a netstring
+        assert!(run(b"abc").is_err());
+        // Missing trailing comma
+        assert!(run(b"24:CONTENT_LENGTH\x000\x00SCGI\x001\x00;").is_err());
+        // CONTENT_LENGTH isn't first
+        assert!(run(b"24:SCGI\x001\x00CONTENT_LENGTH\x000\x00,").is_err());
+    }
+
+    #[test]
+    fn test_body_limits() {
+        let limits = Limits::new().max_body_size(5);
+        assert!(
+            run_within(b"24:CONTENT_LENGTH\x005\x00SCGI\x001\x00,short", &limits)
+                .unwrap()
+                .ends_with("\n\nGET / short")
+        );
+        assert_eq!(
+            run_within(b"24:CONTENT_LENGTH\x006\x00SCGI\x001\x00,longer", &limits).unwrap(),
+            "Status: 413 Payload Too Large\n\n"
+        );
+        // Body shorter than CONTENT_LENGTH
+        assert_eq!(
+            run(b"25:CONTENT_LENGTH\x0010\x00SCGI\x001\x00,short").unwrap(),
+            "Status: 400 Bad Request\n\n"
+        );
+        // A huge CONTENT_LENGTH isn't allocated up front
+        assert_eq!(
+            run(b"43:CONTENT_LENGTH\x0018446744073709551615\x00SCGI\x001\x00,short").unwrap(),
+            "Status: 400 Bad Request\n\n"
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! Running a [`tower::Service`](https://docs.rs/tower-service) as a CGI programme.
+
+use std::fmt::Display;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use tower_service::Service;
+
+use crate::{empty_response, form, Request, RequestExt, Response};
+
+/// The request body passed to a service by [`handle_service`](fn.handle_service.html)
+pub type ServiceBody = Full<Bytes>;
+
+/// The request's URI as the service sees it, with only the `PATH_INFO` as the path, so that the
+/// service's routes don't have to include the script name
+fn service_uri(request: &Request) -> Result<http::Uri, http::Error> {
+    let path_info = request
+        .cgi_meta()
+        .and_then(|meta| meta.path_info.as_deref())
+        .unwrap_or_default();
+    let mut path_and_query = form::percent_encode_path(path_info);
+    if !path_and_query.starts_with('/') {
+        path_and_query.insert(0, '/');
+    }
+    if let Some(query) = request.uri().query() {
+        path_and_query.push('?');
+        path_and_query.push_str(query);
+    }
+
+    let mut parts = request.uri().clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse()?);
+    Ok(http::Uri::from_parts(parts)?)
+}
+
+/// Call the service with the request, and collect the whole response body
+async fn call_service<S, B>(mut service: S, mut request: Request) -> Response
+where
+    S: Service<http::Request<ServiceBody>, Response = http::Response<B>>,
+    S::Error: Display,
+    B: http_body::Body,
+    B::Error: Display,
+{
+    if let Err(err) = std::future::poll_fn(|cx| service.poll_ready(cx)).await {
+        eprintln!("Service isn't ready: {}", err);
+        return empty_response(503);
+    }
+
+    match service_uri(&request) {
+        Ok(uri) => *request.uri_mut() = uri,
+        Err(err) => {
+            eprintln!("Invalid PATH_INFO: {}", err);
+            return empty_response(400);
+        }
+    }
+
+    let response = match service
+        .call(request.map(|body| Full::new(Bytes::from(body))))
+        .await
+    {
+        Ok(response) => response,
+        Err(err) => {
+            eprintln!("Service failed: {}", err);
+            return empty_response(500);
+        }
+    };
+
+    let (parts, body) = response.into_parts();
+    match body.collect().await {
+        Ok(body) => Response::from_parts(parts, body.to_bytes().to_vec()),
+        Err(err) => {
+            eprintln!("Unable to read the response body: {}", err);
+            empty_response(500)
+        }
+    }
+}
+
+/// Run a `tower::Service` (e.g. an axum `Router`) as a CGI programme.
+///
+/// The request is parsed like [`handle`](fn.handle.html) does (so the
+/// [`CgiMeta`](struct.CgiMeta.html) is in the request's extensions), with the body as a
+/// [`ServiceBody`](type.ServiceBody.html). The service is run like
+/// [`handle_async`](fn.handle_async.html) does, and the whole response body is collected before
+/// it's written out.
+///
+/// The service sees the path after the script (`PATH_INFO`) as the request's path, so a route
+/// for `/` matches `/cgi-bin/app.cgi` itself, and `/items` matches `/cgi-bin/app.cgi/items`. The
+/// script name is still in the [`CgiMeta`](struct.CgiMeta.html).
+///
+/// Requires the `tower` feature.
+///
+/// ```rust,ignore
+/// extern crate cgi;
+///
+/// fn main() {
+///     let app = axum::Router::new().route("/", axum::routing::get(|| async { "Hello World" }));
+///     cgi::handle_service(app);
+/// }
+/// ```
+pub fn handle_service<S, B>(service: S)
+where
+    S: Service<http::Request<ServiceBody>, Response = http::Response<B>>,
+    S::Error: Display,
+    B: http_body::Body,
+    B::Error: Display,
+{
+    crate::handle_async(|request| call_service(service, request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::block_on;
+    use crate::testing::TestRequest;
+    use std::future::{ready, Ready};
+    use std::task::{Context, Poll};
+
+    /// Echoes the method, path, remote address & body back
+    struct Echo;
+
+    impl Service<http::Request<ServiceBody>> for Echo {
+        type Response = http::Response<String>;
+        type Error = std::convert::Infallible;
+        type Future = std::pin::Pin<
+            Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>>>,
+        >;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<ServiceBody>) -> Self::Future {
+            Box::pin(async move {
+                let remote_addr = request.cgi_meta().unwrap().remote_addr.unwrap();
+                let head = format!("{} {} {} ", request.method(), request.uri(), remote_addr);
+                let body = request.into_body().collect().await.unwrap().to_bytes();
+                let body = head + &String::from_utf8_lossy(&body);
+                Ok(http::Response::builder()
+                    .status(201)
+                    .header("Content-Type", "text/plain")
+                    .body(body)
+                    .unwrap())
+            })
+        }
+    }
+
+    /// Never ready
+    struct Broken;
+
+    impl Service<http::Request<ServiceBody>> for Broken {
+        type Response = http::Response<String>;
+        type Error = &'static str;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Err("overloaded"))
+        }
+
+        fn call(&mut self, _request: http::Request<ServiceBody>) -> Self::Future {
+            ready(Err("not ready"))
+        }
+    }
+
+    #[test]
+    fn test_call_service() {
+        let response = TestRequest::post("/items?x=1")
+            .remote_addr("192.0.2.1")
+            .body("some body")
+            .run(|request| block_on(call_service(Echo, request)));
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        assert_eq!(
+            response.text(),
+            "POST http://localhost/items?x=1 192.0.2.1 some body"
+        );
+    }
+
+    #[test]
+    fn test_script_name() {
+        let response = TestRequest::get("/cgi-bin/app.cgi/items/caf%C3%A9?x=1")
+            .script_name("/cgi-bin/app.cgi")
+            .remote_addr("192.0.2.1")
+            .run(|request| block_on(call_service(Echo, request)));
+        assert_eq!(
+            response.text(),
+            "GET http://localhost/items/caf%C3%A9?x=1 192.0.2.1 "
+        );
+
+        let response = TestRequest::get("/cgi-bin/app.cgi")
+            .script_name("/cgi-bin/app.cgi")
+            .remote_addr("192.0.2.1")
+            .run(|request| block_on(call_service(Echo, request)));
+        assert_eq!(response.text(), "GET http://localhost/ 192.0.2.1 ");
+    }
+
+    #[test]
+    fn test_service_errors() {
+        let response = TestRequest::get("/").run(|request| block_on(call_service(Broken, request)));
+        assert_eq!(response.status(), 503);
+    }
+}
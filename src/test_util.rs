@@ -0,0 +1,80 @@
+//! Fixtures shared by the tests of several modules.
+
+use std::io::{self, Read, Write};
+
+/// A fake connection, reading from `input` and writing to `output`
+pub(crate) struct Duplex {
+    input: io::Cursor<Vec<u8>>,
+    pub(crate) output: Vec<u8>,
+}
+
+impl Duplex {
+    pub(crate) fn new(input: Vec<u8>) -> Duplex {
+        Duplex {
+            input: io::Cursor::new(input),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An executable shell script, in a directory of its own which is removed when it's dropped
+#[cfg(unix)]
+pub(crate) struct Script {
+    dir: std::path::PathBuf,
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl Script {
+    /// Write `contents` (after a `#!/bin/sh` line) to a script. `name` must be unique between
+    /// tests, as they run at the same time.
+    pub(crate) fn new(name: &str, contents: &str) -> Script {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("cgi-test-{}-{}", name, std::process::id()));
+        // Left behind by an earlier run which crashed, with the same process ID
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("script.sh");
+
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o755)
+            .open(&path)
+            .unwrap();
+        write!(file, "#!/bin/sh\n{}", contents).unwrap();
+        file.sync_all().unwrap();
+        // It can't be run while it's open for writing (ETXTBSY)
+        drop(file);
+
+        Script { dir, path }
+    }
+
+    pub(crate) fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Script {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
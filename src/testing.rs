@@ -0,0 +1,510 @@
+//! Run a handler in-process, without a webserver, for tests.
+//!
+//! A [`TestRequest`](struct.TestRequest.html) is turned into the CGI meta-variables a webserver
+//! would set, and the handler is run through the same parsing & serialization as
+//! [`handle`](../fn.handle.html), so what the test sees is what the webserver would get.
+//!
+//! ```rust
+//! extern crate cgi;
+//! use cgi::testing::TestRequest;
+//! use cgi::RequestExt;
+//!
+//! fn app(request: cgi::Request) -> cgi::Response {
+//!     let meta = request.cgi_meta().unwrap();
+//!     cgi::text_response(200, format!("Hello {}", meta.remote_addr.unwrap()))
+//! }
+//!
+//! let response = TestRequest::get("/hello").remote_addr("192.0.2.1").run(app);
+//! assert_eq!(response.status(), 200);
+//! assert_eq!(response.text(), "Hello 192.0.2.1");
+//! ```
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{Cursor, Read};
+
+use crate::command::{add_header_vars, path_info};
+use crate::form::percent_decode_path;
+use crate::{CgiError, Limits, RequestBody, Response};
+
+/// A request to run a handler with, see the [module docs](index.html).
+#[derive(Debug, Clone)]
+pub struct TestRequest {
+    method: String,
+    uri: String,
+    script_name: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    vars: Vec<(String, String)>,
+    limits: Limits,
+}
+
+impl TestRequest {
+    /// A request with this method for this URI, e.g. `/path?query` or `https://example.com/path`
+    pub fn new(method: impl Into<String>, uri: impl Into<String>) -> TestRequest {
+        TestRequest {
+            method: method.into(),
+            uri: uri.into(),
+            script_name: String::new(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            vars: Vec::new(),
+            limits: Limits::default(),
+        }
+    }
+
+    /// A `GET` request for this URI
+    pub fn get(uri: impl Into<String>) -> TestRequest {
+        TestRequest::new("GET", uri)
+    }
+
+    /// A `POST` request for this URI
+    pub fn post(uri: impl Into<String>) -> TestRequest {
+        TestRequest::new("POST", uri)
+    }
+
+    /// The URL path the script is at (`SCRIPT_NAME`), e.g. `/cgi-bin/app`.
+    ///
+    /// The rest of the URI's path is the `PATH_INFO`. By default the script is at the root, and
+    /// all the path is `PATH_INFO`.
+    pub fn script_name(mut self, script_name: impl Into<String>) -> TestRequest {
+        self.script_name = script_name.into();
+        self
+    }
+
+    /// Add a request header. `Content-Type` becomes `CONTENT_TYPE`, as a webserver would do, and
+    /// `Content-Length` is ignored, as it's set from the body.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> TestRequest {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// The request body (`CONTENT_LENGTH` is set from it)
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> TestRequest {
+        self.body = body.into();
+        self
+    }
+
+    /// The address of the client (`REMOTE_ADDR`), `127.0.0.1` by default
+    pub fn remote_addr(self, remote_addr: impl Into<String>) -> TestRequest {
+        self.var("REMOTE_ADDR", remote_addr)
+    }
+
+    /// Set any meta-variable, e.g. `REMOTE_USER` or `DOCUMENT_ROOT`. This overrides the ones
+    /// calculated from the rest of the request.
+    pub fn var(mut self, name: impl Into<String>, value: impl Into<String>) -> TestRequest {
+        self.vars.push((name.into(), value.into()));
+        self
+    }
+
+    /// Run the handler within these limits on the request body, like
+    /// [`Limits::handle`](../struct.Limits.html#method.handle) would
+    pub fn limits(mut self, limits: Limits) -> TestRequest {
+        self.limits = limits;
+        self
+    }
+
+    /// The meta-variables (RFC 3875 § 4.1) a webserver would set for this request
+    pub fn env_vars(&self) -> HashMap<String, String> {
+        let mut env_vars = HashMap::new();
+        let mut set = |name: &str, value: &str| {
+            env_vars.insert(name.to_owned(), value.to_owned());
+        };
+
+        let (authority, path_and_query) = match self.uri.split_once("://") {
+            Some((scheme, rest)) => {
+                if scheme.eq_ignore_ascii_case("https") {
+                    set("HTTPS", "on");
+                }
+                let (authority, path_and_query) =
+                    rest.split_at(rest.find('/').unwrap_or(rest.len()));
+                let default_port = if scheme.eq_ignore_ascii_case("https") {
+                    "443"
+                } else {
+                    "80"
+                };
+                (Some((authority, default_port)), path_and_query)
+            }
+            None => (None, self.uri.as_str()),
+        };
+        let (path, query) = match path_and_query.split_once('?') {
+            Some((path, query)) => (path, query),
+            None => (path_and_query, ""),
+        };
+
+        let (server_name, server_port) = match authority {
+            Some((authority, default_port)) => {
+                set("HTTP_HOST", authority);
+                match authority.rsplit_once(':') {
+                    Some((host, port)) if !port.contains(']') => (host, port),
+                    _ => (authority, default_port),
+                }
+            }
+            None => ("localhost", "80"),
+        };
+
+        set("GATEWAY_INTERFACE", "CGI/1.1");
+        set("SERVER_PROTOCOL", "HTTP/1.1");
+        set("SERVER_SOFTWARE", "rust-cgi-testing");
+        set("SERVER_NAME", server_name);
+        set("SERVER_PORT", server_port);
+        set("REMOTE_ADDR", "127.0.0.1");
+        set("REQUEST_METHOD", &self.method);
+        set(
+            "REQUEST_URI",
+            if path_and_query.is_empty() {
+                "/"
+            } else {
+                path_and_query
+            },
+        );
+        set("SCRIPT_NAME", &self.script_name);
+        let path_info = path_info(path, &self.script_name);
+        if !path_info.is_empty() {
+            set(
+                "PATH_INFO",
+                &String::from_utf8_lossy(&percent_decode_path(path_info.as_bytes())),
+            );
+        }
+        set("QUERY_STRING", query);
+        if !self.body.is_empty() {
+            set("CONTENT_LENGTH", &self.body.len().to_string());
+        }
+
+        add_header_vars(
+            &mut env_vars,
+            self.headers.iter().map(|(n, v)| (n.as_str(), v.as_str())),
+        );
+
+        for (name, value) in &self.vars {
+            env_vars.insert(name.clone(), value.clone());
+        }
+
+        env_vars
+    }
+
+    pub(crate) fn into_body(self) -> Vec<u8> {
+        self.body
+    }
+
+    /// Run a handler, as [`handle`](../fn.handle.html) would
+    pub fn run<F>(self, func: F) -> TestResponse
+    where
+        F: FnOnce(crate::Request) -> Response,
+    {
+        let mut stdout = Vec::new();
+        crate::handle_with_io(
+            func,
+            self.env_vars(),
+            Cursor::new(self.body),
+            &mut stdout,
+            &self.limits,
+        );
+        TestResponse::from_cgi(stdout, Vec::new())
+    }
+
+    /// Run a handler, as [`try_handle`](../fn.try_handle.html) would. What it logs is in
+    /// [`TestResponse::stderr`](struct.TestResponse.html#method.stderr).
+    pub fn try_run<E, F>(self, func: F) -> TestResponse
+    where
+        E: Debug,
+        F: FnOnce(crate::Request) -> Result<Response, E>,
+    {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        crate::try_handle_with_io(
+            func,
+            self.env_vars(),
+            Cursor::new(self.body),
+            &mut stdout,
+            &mut stderr,
+            &self.limits,
+        );
+        TestResponse::from_cgi(stdout, stderr)
+    }
+
+    /// Run a handler, as [`handle_streaming`](../fn.handle_streaming.html) would
+    pub fn run_streaming<F, B>(self, func: F) -> TestResponse
+    where
+        F: FnOnce(crate::Request) -> http::Response<B>,
+        B: Read,
+    {
+        let mut stdout = Vec::new();
+        crate::handle_streaming_with_io(
+            func,
+            self.env_vars(),
+            Cursor::new(self.body),
+            &mut stdout,
+            &self.limits,
+        );
+        TestResponse::from_cgi(stdout, Vec::new())
+    }
+
+    /// Run a handler, as [`handle_streaming_request`](../fn.handle_streaming_request.html) would.
+    ///
+    /// The body isn't read from stdin here, so to test the same handler with both, make it generic
+    /// over the reader, e.g. `fn app<R: Read>(request: http::Request<cgi::RequestBody<R>>)`.
+    pub fn run_streaming_request<F>(self, func: F) -> TestResponse
+    where
+        F: FnOnce(http::Request<RequestBody<Cursor<Vec<u8>>>>) -> Response,
+    {
+        let mut stdout = Vec::new();
+        crate::handle_streaming_request_with_io(
+            func,
+            self.env_vars(),
+            Cursor::new(self.body),
+            &mut stdout,
+            &self.limits,
+        );
+        TestResponse::from_cgi(stdout, Vec::new())
+    }
+
+    /// Run a handler, as [`handle_nph`](../fn.handle_nph.html) would
+    pub fn run_nph<F>(self, func: F) -> TestResponse
+    where
+        F: FnOnce(crate::Request) -> Response,
+    {
+        let mut stdout = Vec::new();
+        crate::handle_nph_with_io(
+            func,
+            self.env_vars(),
+            Cursor::new(self.body),
+            &mut stdout,
+            &self.limits,
+        );
+
+        // Turn the status line into a `Status` header, so it can be parsed like CGI output
+        let end = stdout.iter().position(|b| *b == b'\n').unwrap_or(0);
+        let status_line = String::from_utf8_lossy(&stdout[..end]).into_owned();
+        let code = status_line.split(' ').nth(1).unwrap_or_default();
+        let mut output = format!("Status: {}\n", code).into_bytes();
+        output.extend_from_slice(&stdout[end + 1..]);
+
+        let (response, output_error) = parse(&output);
+        TestResponse {
+            response,
+            output_error,
+            stdout,
+            stderr: Vec::new(),
+        }
+    }
+}
+
+/// The response a webserver would send for this CGI output
+fn parse(output: &[u8]) -> (Response, Option<CgiError>) {
+    match crate::parse_response(output) {
+        Ok(response) => (response, None),
+        Err(err) => (crate::empty_response(502), Some(err)),
+    }
+}
+
+/// What a handler wrote, and the `Response` the webserver would parse from it.
+///
+/// If the output isn't valid, the response is a `502 Bad Gateway` (as a webserver would send),
+/// and [`output_error`](#method.output_error) says what's wrong with it.
+#[derive(Debug)]
+pub struct TestResponse {
+    response: Response,
+    output_error: Option<CgiError>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl TestResponse {
+    fn from_cgi(stdout: Vec<u8>, stderr: Vec<u8>) -> TestResponse {
+        let (response, output_error) = parse(&stdout);
+        TestResponse {
+            response,
+            output_error,
+            stdout,
+            stderr,
+        }
+    }
+
+    /// Why the output couldn't be parsed as a response, if it couldn't be
+    pub fn output_error(&self) -> Option<&CgiError> {
+        self.output_error.as_ref()
+    }
+
+    /// The status of the response
+    pub fn status(&self) -> http::StatusCode {
+        self.response.status()
+    }
+
+    /// The headers of the response
+    pub fn headers(&self) -> &http::HeaderMap {
+        self.response.headers()
+    }
+
+    /// The body of the response
+    pub fn body(&self) -> &[u8] {
+        self.response.body()
+    }
+
+    /// The body of the response, as text
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.response.body())
+    }
+
+    /// The response, as parsed from the output
+    pub fn response(&self) -> &Response {
+        &self.response
+    }
+
+    /// The response, as parsed from the output
+    pub fn into_response(self) -> Response {
+        self.response
+    }
+
+    /// Exactly what was written to stdout
+    pub fn stdout(&self) -> &[u8] {
+        &self.stdout
+    }
+
+    /// What [`try_run`](struct.TestRequest.html#method.try_run) logged about an error
+    pub fn stderr(&self) -> &[u8] {
+        &self.stderr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RequestExt;
+
+    #[test]
+    fn test_env_vars() {
+        let env_vars = TestRequest::post("https://example.com:8443/cgi-bin/app/a%20b/c?x=1")
+            .script_name("/cgi-bin/app")
+            .header("Content-Type", "text/plain")
+            .header("Accept", "text/html")
+            .header("Accept", "*/*")
+            .body("hello")
+            .var("REMOTE_USER", "amanda")
+            .env_vars();
+
+        let var = |name: &str| env_vars.get(name).map(String::as_str);
+        assert_eq!(var("REQUEST_METHOD"), Some("POST"));
+        assert_eq!(var("REQUEST_URI"), Some("/cgi-bin/app/a%20b/c?x=1"));
+        assert_eq!(var("SCRIPT_NAME"), Some("/cgi-bin/app"));
+        assert_eq!(var("PATH_INFO"), Some("/a b/c"));
+        assert_eq!(var("QUERY_STRING"), Some("x=1"));
+        assert_eq!(var("SERVER_NAME"), Some("example.com"));
+        assert_eq!(var("SERVER_PORT"), Some("8443"));
+        assert_eq!(var("HTTPS"), Some("on"));
+        assert_eq!(var("HTTP_HOST"), Some("example.com:8443"));
+        assert_eq!(var("CONTENT_TYPE"), Some("text/plain"));
+        assert_eq!(var("CONTENT_LENGTH"), Some("5"));
+        assert_eq!(var("HTTP_CONTENT_TYPE"), None);
+        assert_eq!(var("HTTP_ACCEPT"), Some("text/html, */*"));
+        assert_eq!(var("REMOTE_USER"), Some("amanda"));
+
+        // The script name only matches whole segments
+        let env_vars = TestRequest::get("/cgi-bin/application")
+            .script_name("/cgi-bin/app")
+            .env_vars();
+        assert_eq!(env_vars["PATH_INFO"], "/cgi-bin/application");
+        let env_vars = TestRequest::get("/cgi-bin/app")
+            .script_name("/cgi-bin/app")
+            .env_vars();
+        assert!(!env_vars.contains_key("PATH_INFO"));
+    }
+
+    #[test]
+    fn test_run() {
+        let response = TestRequest::post("/form?a=1")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Cookie", "x=1")
+            .header("Cookie", "y=2")
+            .body("b=2")
+            .remote_addr("192.0.2.1")
+            .run(|request| {
+                let form = crate::Form::from_request(&request);
+                let cookies = crate::CookieJar::from_request(&request);
+                assert_eq!(
+                    request.cgi_meta().unwrap().remote_addr,
+                    Some("192.0.2.1".parse().unwrap())
+                );
+                let mut response = crate::text_response(
+                    201,
+                    format!(
+                        "{} {} {}",
+                        form.value("a").unwrap(),
+                        form.value("b").unwrap(),
+                        cookies.get("y").unwrap()
+                    ),
+                );
+                crate::set_cookie(&mut response, &crate::Cookie::new("z", "3")).unwrap();
+                response
+            });
+
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.text(), "1 2 2");
+        assert_eq!(response.headers()["set-cookie"], "z=3");
+        assert!(response.stdout().starts_with(b"Status: 201 Created\n"));
+
+        let response = TestRequest::post("/")
+            .body("too big")
+            .limits(Limits::new().max_body_size(3))
+            .run(|_| unreachable!());
+        assert_eq!(response.status(), 413);
+    }
+
+    #[test]
+    fn test_try_run() {
+        let response = TestRequest::get("/").try_run(|_| Err::<Response, _>("broken"));
+        assert_eq!(response.status(), 500);
+        assert_eq!(response.stderr(), b"\"broken\"\n");
+    }
+
+    #[test]
+    fn test_run_streaming() {
+        let response = TestRequest::get("/export")
+            .run_streaming(|_| crate::streaming_response(200, "text/csv", "a,b\n1,2\n".as_bytes()));
+        assert_eq!(response.headers()["content-type"], "text/csv");
+        assert_eq!(response.text(), "a,b\n1,2\n");
+    }
+
+    #[test]
+    fn test_run_streaming_request() {
+        let response = TestRequest::post("/upload")
+            .body("some body")
+            .run_streaming_request(|mut request| {
+                let mut body = String::new();
+                request.body_mut().read_to_string(&mut body).unwrap();
+                crate::text_response(200, body)
+            });
+        assert_eq!(response.text(), "some body");
+    }
+
+    #[test]
+    fn test_invalid_output() {
+        let response = TestResponse::from_cgi(b"Not a header\n\nbody".to_vec(), Vec::new());
+        assert_eq!(response.status(), 502);
+        assert!(response.output_error().is_some());
+        assert_eq!(response.stdout(), b"Not a header\n\nbody");
+
+        let response = TestRequest::get("/").run(|_| crate::empty_response(204));
+        assert!(response.output_error().is_none());
+    }
+
+    #[test]
+    fn test_run_nph() {
+        let response = TestRequest::get("/").run_nph(|_| crate::text_response(404, "Nope"));
+        assert_eq!(response.status(), 404);
+        assert!(response.headers().contains_key("date"));
+        assert_eq!(response.text(), "Nope");
+        assert!(response.stdout().starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_redirects() {
+        let response = TestRequest::get("/").run(|_| crate::local_redirect("/other").unwrap());
+        assert_eq!(response.stdout(), b"Location: /other\n\n");
+        assert_eq!(response.headers()["location"], "/other");
+
+        let response = TestRequest::get("/").run(|_| crate::redirect_302("https://example.com/"));
+        assert_eq!(response.status(), 302);
+    }
+}